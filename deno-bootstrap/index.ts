@@ -1,64 +1,258 @@
+// The version of the protocol between this script and the worker, reported
+// on every response. Must match PROTOCOL_VERSION in src/DenoHTTPWorker.ts.
+const PROTOCOL_VERSION = 1;
+
 const socketFile = Deno.args[0];
 const scriptType = Deno.args[1];
-const script = Deno.args[2];
+// Scripts sent over stdin are kept out of the process arguments.
+const script =
+  scriptType == "stdin"
+    ? await new Response(Deno.stdin.readable).text()
+    : Deno.args[2];
 
 const importURL =
   scriptType == "import"
     ? script
     : "data:text/tsx," + encodeURIComponent(script);
 
+// Bootstrap flags follow the script, and the script's own arguments follow a
+// "--" after those.
+const bootstrapFlags = Deno.args.slice(scriptType == "stdin" ? 2 : 3);
+const separator = bootstrapFlags.indexOf("--");
+if (separator !== -1) bootstrapFlags.length = separator;
+// Each request gets its own instance of the script's module.
+const perRequest = bootstrapFlags.includes("--isolation=per-request");
+
+// Returns a URL that loads the script as a new module instance. A different
+// URL makes Deno load the module again instead of returning the cached one.
+function freshScriptURL(kind: string, n: number): string {
+  if (scriptType == "import") {
+    const url = new URL(script);
+    url.searchParams.set(`deno-http-worker-${kind}`, String(n));
+    return url.href;
+  }
+  return (
+    "data:text/tsx," +
+    encodeURIComponent(`${script}\n// deno-http-worker-${kind}:${n}`)
+  );
+}
+let instances = 0;
+
+type Handler = { fetch: (req: Request) => Response | Promise<Response> };
+
+function checkHandler(mod: { default?: Handler }): Handler {
+  if (!mod.default) {
+    throw new Error("No default export found in script.");
+  }
+  if (typeof mod.default.fetch !== "function") {
+    throw new Error("Default export does not have a fetch function.");
+  }
+  return mod.default;
+}
+
+// Modules to load up front, so that the script's dynamic imports of them
+// don't slow down its first request.
+await Promise.all(
+  bootstrapFlags
+    .filter((flag) => flag.startsWith("--preload="))
+    .map((flag) => import(flag.slice("--preload=".length)))
+);
+
 const mod = await import(importURL);
-if (!mod.default) {
-  throw new Error("No default export found in script.");
+// Replaced when the script is reloaded through the control socket. A script
+// without a valid handler still starts, so that the warm request can report
+// why instead of the process exiting with a stack trace.
+let main: Handler | undefined;
+let invalidHandler = "";
+try {
+  main = checkHandler(mod);
+} catch (e) {
+  invalidHandler = (e as Error).message;
+}
+
+// Scripts registered at runtime through the control socket, selected per
+// request by the X-Deno-Worker-Route header.
+const routes = new Map<string, Handler>();
+let registrations = 0;
+
+async function register(key: string, source: string): Promise<Response> {
+  // Each registration gets a unique URL so that registering the same source
+  // twice still loads a separate module instance.
+  const url =
+    "data:text/tsx," +
+    encodeURIComponent(`${source}\n// deno-http-worker:${registrations++}`);
+  try {
+    routes.set(key, checkHandler(await import(url)));
+  } catch (e) {
+    return new Response(String(e), { status: 400 });
+  }
+  return new Response(null, { status: 204 });
 }
-if (typeof mod.default.fetch !== "function") {
-  throw new Error("Default export does not have a fetch function.");
+
+// Deno 2 throws NotCapable instead of PermissionDenied for missing
+// permissions.
+function isPermissionError(error: unknown): error is Error {
+  const NotCapable = (Deno.errors as Record<string, unknown>).NotCapable;
+  return (
+    error instanceof Deno.errors.PermissionDenied ||
+    (typeof NotCapable === "function" && error instanceof NotCapable)
+  );
 }
 
 const onError =
-  mod.default.onError ??
+  mod.default?.onError ??
   function (error: unknown) {
     console.error(error);
+    if (isPermissionError(error)) {
+      return new Response(error.message, {
+        status: 500,
+        headers: { "X-Deno-Worker-Error": "PermissionDenied" },
+      });
+    }
     return new Response("Internal Server Error", { status: 500 });
   };
-const onListen = mod.default.onListen ?? function (_localAddr: Deno.NetAddr) {};
+const onListen =
+  mod.default?.onListen ?? function (_localAddr: Deno.NetAddr) {};
+
+// When running with --watch a restarted script finds the socket file left
+// behind by the previous run.
+try {
+  Deno.removeSync(socketFile);
+} catch {
+  // The socket file doesn't exist yet.
+}
+
+// Handler calls that haven't returned a response yet, and all calls so far.
+let inFlight = 0;
+let served = 0;
+
+// Every response carries the protocol version, so that the worker can check
+// it whichever handler answers its warm request. Responses with immutable
+// headers, such as ones returned by fetch, are copied first; a WebSocket
+// upgrade can't be, so it is sent as is.
+function withProtocol(resp: Response): Response {
+  try {
+    resp.headers.set("X-Deno-Worker-Protocol", String(PROTOCOL_VERSION));
+  } catch {
+    if (resp.status === 101) return resp;
+    resp = new Response(resp.body, resp);
+    resp.headers.set("X-Deno-Worker-Protocol", String(PROTOCOL_VERSION));
+  }
+  return resp;
+}
+
+function handle(req: Request): Response | Promise<Response> {
+  const routeKey = req.headers.get("X-Deno-Worker-Route");
+  const handler = routeKey === null ? main : routes.get(routeKey);
+  if (routeKey !== null && !handler) {
+    return new Response(`No script registered for route ${routeKey}`, {
+      status: 404,
+    });
+  }
+  if (!handler) {
+    return new Response(invalidHandler, {
+      status: 500,
+      headers: { "X-Deno-Worker-Error": "InvalidHandler" },
+    });
+  }
+
+  const headerUrl = req.headers.get("X-Deno-Worker-URL");
+  if (!headerUrl) {
+    // This is just for the warming request, shouldn't be seen by clients.
+    return Response.json({ warming: true });
+  }
+  const url = new URL(headerUrl);
+  // Deno Request headers are immutable so we must make a new Request in order
+  // to delete our headers.
+  req = new Request(url.toString(), req);
+
+  // Restore host and connection headers.
+  req.headers.delete("host");
+  req.headers.delete("connection");
+  if (req.headers.has("X-Deno-Worker-Host"))
+    req.headers.set("host", req.headers.get("X-Deno-Worker-Host")!);
+  if (req.headers.has("X-Deno-Worker-Connection"))
+    req.headers.set(
+      "connection",
+      req.headers.get("X-Deno-Worker-Connection")!
+    );
+
+  req.headers.delete("X-Deno-Worker-URL");
+  req.headers.delete("X-Deno-Worker-Host");
+  req.headers.delete("X-Deno-Worker-Connection");
+  req.headers.delete("X-Deno-Worker-Route");
+  inFlight++;
+  served++;
+  const instance =
+    perRequest && routeKey === null
+      ? import(freshScriptURL("instance", ++instances)).then(checkHandler)
+      : Promise.resolve(handler);
+  return instance
+    .then((loaded) => loaded.fetch(req))
+    .finally(() => inFlight--);
+}
 
 // Use an empty onListen callback to prevent Deno from logging
 const server = Deno.serve(
   {
     path: socketFile,
     onListen: onListen,
-    onError: onError,
+    onError: (error: unknown) =>
+      Promise.resolve(onError(error)).then(withProtocol),
   },
-  (req: Request) => {
-    const headerUrl = req.headers.get("X-Deno-Worker-URL");
-    if (!headerUrl) {
-      // This is just for the warming request, shouldn't be seen by clients.
-      return Response.json({ warming: true }, { status: 200 });
-    }
-    const url = new URL(headerUrl);
-    // Deno Request headers are immutable so we must make a new Request in order
-    // to delete our headers.
-    req = new Request(url.toString(), req);
-
-    // Restore host and connection headers.
-    req.headers.delete("host");
-    req.headers.delete("connection");
-    if (req.headers.has("X-Deno-Worker-Host"))
-      req.headers.set("host", req.headers.get("X-Deno-Worker-Host")!);
-    if (req.headers.has("X-Deno-Worker-Connection"))
-      req.headers.set(
-        "connection",
-        req.headers.get("X-Deno-Worker-Connection")!
-      );
-
-    req.headers.delete("X-Deno-Worker-URL");
-    req.headers.delete("X-Deno-Worker-Host");
-    req.headers.delete("X-Deno-Worker-Connection");
-    return mod.default.fetch(req);
-  }
+  (req: Request) => Promise.resolve(handle(req)).then(withProtocol)
 );
 
+// The optional control socket takes admin commands, kept apart from the
+// requests on the main socket.
+const controlSocketFile = bootstrapFlags
+  .find((flag) => flag.startsWith("--control-socket="))
+  ?.slice("--control-socket=".length);
+let reloads = 0;
+
+async function reload(): Promise<Response> {
+  reloads++;
+  try {
+    main = checkHandler(await import(freshScriptURL("reload", reloads)));
+    invalidHandler = "";
+  } catch (e) {
+    return new Response(String(e), { status: 400 });
+  }
+  return new Response(null, { status: 204 });
+}
+
+let controlServer: Deno.HttpServer | undefined;
+if (controlSocketFile) {
+  try {
+    Deno.removeSync(controlSocketFile);
+  } catch {
+    // The socket file doesn't exist yet.
+  }
+  controlServer = Deno.serve(
+    { path: controlSocketFile, onListen() {} },
+    async (req: Request) => {
+      const url = new URL(req.url);
+      switch (url.pathname) {
+        case "/reload":
+          return reload();
+        case "/register":
+          return register(url.searchParams.get("key") ?? "", await req.text());
+        case "/drain":
+          await server.shutdown();
+          return new Response(null, { status: 204 });
+        case "/stats":
+          return Response.json({
+            inFlight,
+            requestsServed: served,
+            memory: Deno.memoryUsage(),
+          });
+        default:
+          return new Response("Unknown command", { status: 404 });
+      }
+    }
+  );
+}
+
 globalThis.onerror = (e) => {
   console.error(e.error);
   e.preventDefault();
@@ -67,5 +261,5 @@ globalThis.onerror = (e) => {
 Deno.addSignalListener("SIGINT", async () => {
   // On interrupt we only shut down the server. Deno will wait for all
   // unresolved promises to complete before exiting.
-  await server.shutdown();
+  await Promise.all([server.shutdown(), controlServer?.shutdown()]);
 });
@@ -1,7 +1,43 @@
 import { it as _it, beforeAll, describe, expect } from "vitest";
-import { DenoHTTPWorker, newDenoHTTPWorker } from "./index.js";
+import {
+  BootstrapCompileError,
+  DenoHTTPWorker,
+  DenoNotFoundError,
+  IncompleteResponseError,
+  InvalidConfigError,
+  InvalidHandlerError,
+  InvalidUrlError,
+  LogLine,
+  newDenoHTTPWorker,
+  newDenoHTTPWorkerFromBytes,
+  newDenoHTTPWorkerFromPath,
+  newDenoHTTPWorkers,
+  PermissionDeniedError,
+  ProtocolMismatchError,
+  RequestTimeoutError,
+  resolveWorkerCommand,
+  ResponseDecodeError,
+  ResponseTooLargeError,
+  ScriptRegistrationError,
+  SocketDirNotWritableError,
+  SocketGoneError,
+  SocketPathTooLongError,
+  StartupTimeoutError,
+  WorkerExitedDuringRequestError,
+  withForwardedFor,
+} from "./index.js";
+import {
+  STALLED_READER_SCRIPT,
+  echoChunksIncrementally,
+  newStreamingEchoWorker,
+  writeUntilBackpressure,
+} from "./test-util.js";
 import fs from "fs";
+import http from "http";
 import path from "path";
+import os from "os";
+import { Readable } from "stream";
+import { pathToFileURL } from "url";
 import { Worker } from "worker_threads";
 import { SpawnOptions, spawn } from "child_process";
 
@@ -72,6 +108,134 @@ describe("DenoHTTPWorker", { timeout: 1000 }, () => {
     worker.terminate();
   });
 
+  it("onLog receives tagged output lines", async () => {
+    const lines: LogLine[] = [];
+    const worker = await newDenoHTTPWorker(
+      `
+        console.log("hello from stdout");
+        console.error("hello from stderr");
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: req.url })
+        }}
+      `,
+      { name: "logger", onLog: (line) => lines.push(line) }
+    );
+    await worker.requestCollect("https://localhost/");
+    expect(lines).toContainEqual({
+      stream: "stdout",
+      line: "hello from stdout",
+      name: "logger",
+      pid: expect.any(Number),
+    });
+    expect(lines).toContainEqual({
+      stream: "stderr",
+      line: "hello from stderr",
+      name: "logger",
+      pid: expect.any(Number),
+    });
+    worker.terminate();
+  });
+
+  it("keeps reading output that nobody else reads", async () => {
+    let count = 0;
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          // Far more than the pipe and stream buffers hold.
+          for (let i = 0; i < 2000; i++) console.log("x".repeat(100));
+          return Response.json({ ok: true })
+        }}
+      `,
+      { onLog: () => count++ }
+    );
+    for (let i = 0; i < 3; i++) {
+      expect(await jsonRequest(worker, "https://localhost/")).toEqual({
+        ok: true,
+      });
+    }
+    // Wait for all of the output to be read.
+    worker.shutdown();
+    await worker.runToCompletion();
+    expect(count).toEqual(6000);
+  }, 5000);
+
+  it("withProcess exposes the child until terminated", async () => {
+    let pid: number | undefined;
+    const worker = await newDenoHTTPWorker(echoScript, {
+      onSpawn: (process) => {
+        pid = process.pid;
+      },
+    });
+    expect(worker.withProcess((process) => process.pid)).toEqual(pid);
+    worker.terminate();
+    expect(worker.withProcess((process) => process.pid)).toBeUndefined();
+  });
+
+  it("capturedLogs returns output since start", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        console.log("started");
+        export default { async fetch (req: Request): Promise<Response> {
+          console.error("handled", req.url);
+          return Response.json({ ok: req.url })
+        }}
+      `
+    );
+    await worker.requestCollect("https://localhost/logged");
+    // The handler's output may arrive slightly after the response.
+    while (worker.capturedLogs().length < 2) {
+      await new Promise((resolve) => setTimeout(resolve, 10));
+    }
+    const logs = worker.capturedLogs().map(({ stream, line }) => [stream, line]);
+    expect(logs).toEqual([
+      ["stdout", "started"],
+      ["stderr", "handled https://localhost/logged"],
+    ]);
+    worker.terminate();
+  });
+
+  it.each([
+    ["dropOldest", ["3", "4"]],
+    ["dropNewest", ["0", "1"]],
+  ] as const)("capturedLogOverflow %s", async (overflow, expected) => {
+    const worker = await newDenoHTTPWorker(
+      `
+        for (let i = 0; i < 5; i++) console.log(String(i));
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: true })
+        }}
+      `,
+      { capturedLogLines: 2, capturedLogOverflow: overflow }
+    );
+    // Wait for all of the output to be read.
+    worker.shutdown();
+    await worker.runToCompletion();
+    const lines = worker.capturedLogs().map(({ line }) => line);
+    expect(lines).toEqual(expected);
+  });
+
+  it("parseJSONLogs parses structured stderr lines", async () => {
+    const lines: LogLine[] = [];
+    const worker = await newDenoHTTPWorker(
+      `
+        console.error(JSON.stringify({ level: "info", msg: "structured" }));
+        console.error("plain");
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: req.url })
+        }}
+      `,
+      { parseJSONLogs: true, onLog: (line) => lines.push(line) }
+    );
+    expect(lines.map(({ line, json }) => [line, json])).toEqual([
+      [
+        '{"level":"info","msg":"structured"}',
+        { level: "info", msg: "structured" },
+      ],
+      ["plain", undefined],
+    ]);
+    worker.terminate();
+  });
+
   it("alternate spawnFunc can be provided", async () => {
     let firstArg: string = "";
     const worker = await newDenoHTTPWorker(
@@ -95,231 +259,2293 @@ describe("DenoHTTPWorker", { timeout: 1000 }, () => {
     worker.terminate();
   });
 
-  it("dont crash on socket removal", async () => {
+  it.each([[[]], [""], [["", "run"]]])(
+    "rejects empty denoExecutable %j",
+    async (denoExecutable) => {
+      await expect(
+        newDenoHTTPWorker(DEFAULT_HTTP_VAL, { denoExecutable })
+      ).rejects.toBeInstanceOf(InvalidConfigError);
+    }
+  );
+
+  it("warmConnections opens several connections", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      warmConnections: 4,
+    });
+    const responses = await Promise.all(
+      [1, 2, 3, 4].map(() => jsonRequest(worker, "http://localhost/"))
+    );
+    expect(responses).toHaveLength(4);
+    worker.terminate();
+
+    await expect(
+      newDenoHTTPWorker(echoScript, { warmConnections: 0 })
+    ).rejects.toBeInstanceOf(InvalidConfigError);
+  });
+
+  it("connectTimeout allows connections made in time", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      connectTimeout: 1000,
+    });
+    const json = await jsonRequest(worker, "http://localhost/connected");
+    expect(json.url).toEqual("http://localhost/connected");
+    worker.terminate();
+
+    await expect(
+      newDenoHTTPWorker(echoScript, { connectTimeout: 0 })
+    ).rejects.toBeInstanceOf(InvalidConfigError);
+  });
+
+  it("warmRequestURL sends marked warm requests to the script", async () => {
     const worker = await newDenoHTTPWorker(
       `
+        const warmups: string[] = [];
         export default { async fetch (req: Request): Promise<Response> {
-          await Deno.removeSync(Deno.args[0]);
-          return Response.json({ ok: req.url })
-        } }
+          if (req.headers.get("x-deno-worker-warmup") === "1") {
+            warmups.push(req.url);
+            return new Response("warm");
+          }
+          return Response.json({ warmups })
+        }}
       `,
-      { printOutput: true }
+      {
+        printOutput: true,
+        warmConnections: 2,
+        warmRequestURL: "http://localhost/_warm",
+      }
     );
-    const json = await jsonRequest(worker, "https://localhost/hello?isee=you", {
-      headers: { accept: "application/json" },
-    });
-    expect(json).toEqual({
-      ok: "https://localhost/hello?isee=you",
+    const json = await jsonRequest(worker, "http://localhost/", {
+      headers: { "x-deno-worker-warmup": "1" },
     });
+    expect(json.warmups).toEqual([
+      "http://localhost/_warm",
+      "http://localhost/_warm",
+    ]);
     worker.terminate();
   });
 
-  it("json response multiple requests", async () => {
+  it("newDenoHTTPWorkers starts several workers at once", async () => {
+    const workers = await newDenoHTTPWorkers(echoScript, 3, {
+      printOutput: true,
+    });
+    const pids = workers.map((w) => w.withProcess((p) => p.pid));
+    expect(new Set(pids).size).toEqual(3);
+    for (const worker of workers) {
+      await jsonRequest(worker, "http://localhost/");
+      worker.terminate();
+    }
+
+    // A failure terminates the workers that did start.
+    let spawned = 0;
+    const exits: number[] = [];
+    const err = await newDenoHTTPWorkers(echoScript, 2, {
+      onSpawn: (process) => {
+        if (spawned++ === 0) {
+          process.on("exit", (code: number) => exits.push(code));
+        } else {
+          process.kill("SIGKILL");
+        }
+      },
+    }).catch((err) => err);
+    expect(err).toBeInstanceOf(Error);
+    await new Promise((resolve) => setTimeout(resolve, 100));
+    expect(exits).toHaveLength(1);
+  });
+
+  it("reports a missing deno executable", async () => {
+    const err = await newDenoHTTPWorker(echoScript, {
+      denoExecutable: "deno-http-worker-missing-deno",
+    }).catch((err) => err);
+    expect(err).toBeInstanceOf(DenoNotFoundError);
+    expect(err.phase).toEqual("spawn");
+    expect(err.executable).toEqual("deno-http-worker-missing-deno");
+    expect(err.message).toContain("Install Deno");
+  });
+
+  it("scriptArgs are passed to the script", async () => {
     const worker = await newDenoHTTPWorker(
       `
+        const args = Deno.args.slice(Deno.args.indexOf("--") + 1);
         export default { async fetch (req: Request): Promise<Response> {
-          let headers = {};
-          for (let [key, value] of req.headers.entries()) {
-            headers[key] = value;
-          }
-          return Response.json({ ok: req.url, headers: headers })
-        } }
+          return Response.json(args)
+        }}
       `,
-      { printOutput: true }
+      { printOutput: true, scriptArgs: ["--port", "80", "--"] }
     );
-    for (let i = 0; i < 10; i++) {
-      const json = await jsonRequest(
-        worker,
-        "https://localhost/hello?isee=you",
-        { headers: { accept: "application/json" } }
-      );
-      expect(json).toEqual({
-        ok: "https://localhost/hello?isee=you",
-        headers: { accept: "application/json" },
-      });
-    }
+    expect(await jsonRequest(worker, "https://localhost/")).toEqual([
+      "--port",
+      "80",
+      "--",
+    ]);
     worker.terminate();
   });
 
-  it("onError", async () => {
+  it("the script gets no arguments without scriptArgs", async () => {
     const worker = await newDenoHTTPWorker(
       `
+        const args = Deno.args.slice(Deno.args.indexOf("--") + 1);
         export default { async fetch (req: Request): Promise<Response> {
-          return {} // not a response
-        }, onError (error: Error): Response {
-          return Response.json({ error: error.message }, { status: 500 })
+          return Response.json(args)
         }}
       `,
       { printOutput: true }
     );
-    const json = await jsonRequest(worker, "https://localhost/hello?isee=you", {
-      headers: { accept: "application/json" },
-    });
-    expect(json).toEqual({
-      error:
-        "Return value from serve handler must be a response or a promise resolving to a response",
-    });
-
+    expect(await jsonRequest(worker, "https://localhost/")).toEqual([]);
     worker.terminate();
   });
-  it("onError not handled", async () => {
-    // onError is not called in all cases, for example, here I can pass a
-    // readable stream and the error is only caught by the global onerror handler.
+
+  it("denoDir sets DENO_DIR for the process", async () => {
+    const denoDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-dir-"));
     const worker = await newDenoHTTPWorker(
       `
         export default { async fetch (req: Request): Promise<Response> {
-          const body = new ReadableStream({
-            start(controller) {
-              setTimeout(() => {
-                controller.enqueue(1);
-              }, 10);
-            },
-            cancel() {},
-          });
-          return new Response(body)
-        }, onError (error: Error): Response {
-          return Response.json({ error: error.message }, { status: 500 })
+          return Response.json({ denoDir: Deno.env.get("DENO_DIR") })
         }}
       `,
-      { printOutput: false }
+      { printOutput: true, denoDir, runFlags: ["--allow-env=DENO_DIR"] }
     );
-    jsonRequest(worker, "https://localhost/hello?isee=you", {
-      headers: { accept: "application/json" },
-    }).catch(() => {});
-
-    for (;;) {
-      const stderr = worker.stderr.read();
-      if (stderr) {
-        console.log(stderr.toString());
-        expect(stderr.toString()).toContain("expected typed ArrayBufferView");
-        break;
-      }
-      await new Promise((resolve) => setTimeout(resolve, 100));
-    }
+    const json = await jsonRequest(worker, "https://localhost/");
+    expect(json.denoDir).toEqual(denoDir);
     worker.terminate();
+    fs.rmSync(denoDir, { recursive: true, force: true });
   });
 
-  it("shutdown gracefully", async () => {
+  it.skipIf(os.platform() !== "linux")(
+    "peakRSS is reported while running and on exit",
+    async () => {
+      const worker = await newDenoHTTPWorker(echoScript, {
+        printOutput: true,
+      });
+      await jsonRequest(worker, "https://localhost/");
+      const peak = worker.peakRSS as number;
+      expect(peak).toBeGreaterThan(0);
+      const exitPeak = new Promise<number | undefined>((resolve) => {
+        worker.addEventListener("exit", (_code, _signal, { peakRSS }) =>
+          resolve(peakRSS)
+        );
+      });
+      worker.terminate();
+      expect(await exitPeak).toBeGreaterThanOrEqual(peak);
+    }
+  );
+
+  it.skipIf(os.platform() !== "linux")(
+    "cpuAffinity pins the process to the given cores",
+    async () => {
+      const worker = await newDenoHTTPWorker(
+        `
+          export default { async fetch (req: Request): Promise<Response> {
+            return new Response(Deno.readTextFileSync("/proc/self/status"))
+          }}
+        `,
+        {
+          printOutput: true,
+          allowReadPaths: ["/proc/self/status"],
+          cpuAffinity: [0],
+        }
+      );
+      const status = (
+        await worker.requestCollect("https://localhost/")
+      ).body.toString();
+      expect(status).toMatch(/^Cpus_allowed_list:\s+0$/m);
+      worker.terminate();
+    }
+  );
+
+  it.skipIf(os.platform() !== "linux")(
+    "resourceLimits are applied to the process",
+    async () => {
+      const worker = await newDenoHTTPWorker(
+        `
+          export default { async fetch (req: Request): Promise<Response> {
+            return new Response(Deno.readTextFileSync("/proc/self/limits"))
+          }}
+        `,
+        {
+          printOutput: true,
+          allowReadPaths: ["/proc/self/limits"],
+          resourceLimits: { openFiles: 256, cpuSeconds: 60 },
+        }
+      );
+      const limits = (
+        await worker.requestCollect("https://localhost/")
+      ).body.toString();
+      expect(limits).toMatch(/Max open files\s+256\s+256/);
+      expect(limits).toMatch(/Max cpu time\s+60\s+60/);
+      worker.terminate();
+
+      await expect(
+        newDenoHTTPWorker(echoScript, { resourceLimits: { openFiles: -1 } })
+      ).rejects.toBeInstanceOf(InvalidConfigError);
+    }
+  );
+
+  it.skipIf(os.platform() !== "linux")(
+    "reports a missing deno executable behind resourceLimits",
+    async () => {
+      const err = await newDenoHTTPWorker(echoScript, {
+        denoExecutable: "deno-http-worker-missing-deno",
+        resourceLimits: { openFiles: 256 },
+      }).catch((err) => err);
+      expect(err).toBeInstanceOf(DenoNotFoundError);
+      expect(err.phase).toEqual("spawn");
+      expect(err.executable).toEqual("deno-http-worker-missing-deno");
+    }
+  );
+
+  it("egressProxy sends the script's fetch calls to a proxy", async () => {
+    const proxied: string[] = [];
+    const proxy = http.createServer((req, res) => {
+      // Requests to a proxy carry the absolute target URL.
+      proxied.push(req.url as string);
+      res.end("from proxy");
+    });
+    await new Promise<void>((resolve) =>
+      proxy.listen(0, "127.0.0.1", resolve)
+    );
+    const { port } = proxy.address() as { port: number };
+    let args: string[] = [];
     const worker = await newDenoHTTPWorker(
       `
         export default { async fetch (req: Request): Promise<Response> {
-          new Promise((resolve) => setTimeout(() => {resolve(); console.log("hi")}, 200));
-          return Response.json({ ok: req.url })
+          return fetch("http://upstream.example/path")
         }}
       `,
-      { printOutput: true }
+      {
+        printOutput: true,
+        egressProxy: `http://127.0.0.1:${port}`,
+        runFlags: ["--allow-net=upstream.example"],
+        spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+          args = a;
+          return spawn(command, a, options);
+        },
+      }
     );
+    expect(args).toContain(`--allow-net=upstream.example,127.0.0.1:${port}`);
+    const resp = await worker.requestCollect("https://localhost/");
+    expect(resp.body.toString()).toEqual("from proxy");
+    expect(proxied).toEqual(["http://upstream.example/path"]);
+    worker.terminate();
+    proxy.close();
+  });
 
-    let logs = "";
-    worker.stderr.on("data", (data) => (logs += data));
-    worker.stdout.on("data", (data) => (logs += data));
+  it("startupTimeout reports the output of a stalled startup", async () => {
+    const err = await newDenoHTTPWorker(
+      `
+        console.log("still loading");
+        await new Promise((resolve) => setTimeout(resolve, 10000));
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: true })
+        }}
+      `,
+      { startupTimeout: 300 }
+    ).catch((err) => err);
+    expect(err).toBeInstanceOf(StartupTimeoutError);
+    expect(err.stillRunning).toEqual(true);
+    expect(err.stdoutTail).toContain("still loading");
+  });
 
-    const exitPromise = new Promise<void>((resolve) => {
-      worker.addEventListener("exit", (code) => {
-        expect(code).toEqual(0);
-        expect(logs).toContain("hi");
-        resolve();
-      });
+  it("socketDir chooses where the socket is created", async () => {
+    const socketDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-sock-"));
+    const worker = await newDenoHTTPWorker(echoScript, { socketDir });
+    const [socketName] = fs.readdirSync(socketDir);
+    expect(socketName).toMatch(/-deno-http\.sock$/);
+    await jsonRequest(worker, "http://localhost/");
+    await worker.close();
+    expect(fs.readdirSync(socketDir)).toEqual([]);
+    fs.rmSync(socketDir, { recursive: true });
+  });
+
+  it("shutdownAndWait reports whether the process exited", async () => {
+    const worker = await newDenoHTTPWorker(echoScript);
+    expect(await worker.shutdownAndWait(500)).toEqual({
+      code: 0,
+      signal: "",
     });
-    const json = await jsonRequest(worker, "https://localhost/hello?isee=you");
-    expect(json).toEqual({
-      ok: "https://localhost/hello?isee=you",
+
+    const stubborn = await newDenoHTTPWorker(`
+      Deno.addSignalListener("SIGINT", () => {});
+      setInterval(() => {}, 1000);
+      export default { async fetch (req: Request): Promise<Response> {
+        return new Response("ok")
+      }}
+    `);
+    expect(await stubborn.shutdownAndWait(100)).toBeUndefined();
+    expect(stubborn.terminated).toBe(false);
+    stubborn.terminate();
+  });
+
+  it("removes the socket files once the process exits", async () => {
+    const socketDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-sock-"));
+    const worker = await newDenoHTTPWorker(echoScript, {
+      socketDir,
+      controlSocket: true,
     });
+    expect(fs.readdirSync(socketDir)).toHaveLength(2);
     worker.shutdown();
-    await exitPromise;
+    await worker.runToCompletion();
+    expect(fs.readdirSync(socketDir)).toEqual([]);
+    fs.rmSync(socketDir, { recursive: true });
   });
 
-  describe("runFlags editing", () => {
-    it.each([
-      "--allow-read",
-      "--allow-write",
-      "--allow-read=/dev/null",
-      "--allow-write=/dev/null",
-      "--allow-read=foo,/dev/null",
-      "--allow-write=bar,/dev/null",
-    ])("should handle %s", async (flag) => {
-      const worker = await newDenoHTTPWorker(echoScript, {
-        printOutput: true,
-        runFlags: [flag],
-      });
-      await jsonRequest(worker, "http://localhost");
-      await worker.terminate();
+  it("keepSocketOnExit leaves the socket file in place", async () => {
+    const socketDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-sock-"));
+    const worker = await newDenoHTTPWorker(echoScript, {
+      socketDir,
+      keepSocketOnExit: true,
     });
+    const files = fs.readdirSync(socketDir);
+    await worker.close();
+    expect(fs.readdirSync(socketDir)).toEqual(files);
+    fs.rmSync(socketDir, { recursive: true });
   });
 
-  it("should be able to import script", async () => {
-    const file = path.resolve(__dirname, "./test/echo-request.ts");
-    const url = new URL(`file://${file}`);
-    const worker = await newDenoHTTPWorker(url, {
-      printOutput: true,
-      printCommandAndArguments: true,
+  it("socketPath is the socket the process listens on", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const body = await new Promise<string>((resolve, reject) => {
+      const req = http.request(
+        {
+          socketPath: worker.socketPath,
+          path: "/",
+          headers: { "X-Deno-Worker-URL": "https://localhost/direct" },
+        },
+        (resp) => {
+          const chunks: Buffer[] = [];
+          resp.on("data", (chunk) => chunks.push(chunk));
+          resp.on("end", () => resolve(Buffer.concat(chunks).toString()));
+        }
+      );
+      req.on("error", reject);
+      req.end();
     });
+    expect(JSON.parse(body).url).toEqual("https://localhost/direct");
+    worker.terminate();
+  });
 
-    await jsonRequest(worker, "http://localhost");
-    await worker.terminate();
+  it("socketPrefix is part of the socket name", async () => {
+    const socketDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-sock-"));
+    const worker = await newDenoHTTPWorker(echoScript, {
+      socketDir,
+      socketPrefix: "tenant-42",
+    });
+    const [socketName] = fs.readdirSync(socketDir);
+    expect(socketName).toMatch(/^tenant-42-.+-deno-http\.sock$/);
+    await jsonRequest(worker, "http://localhost/");
+    await worker.close();
+    fs.rmSync(socketDir, { recursive: true });
   });
 
-  it("host and connection is not overwritten", async () => {
+  it("rejects a socketPrefix with a path separator", async () => {
+    const err = await newDenoHTTPWorker(echoScript, {
+      socketPrefix: "../escape",
+    }).catch((err) => err);
+    expect(err).toBeInstanceOf(InvalidConfigError);
+  });
+
+  it("generateSocketId sets the unique part of the socket name", async () => {
+    const socketDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-sock-"));
+    let next = 0;
     const worker = await newDenoHTTPWorker(echoScript, {
-      printOutput: true,
+      socketDir,
+      socketPrefix: "tenant-42",
+      generateSocketId: () => `id-${next++}`,
     });
-    const resp: any = await jsonRequest(worker, "https://localhost/", {
+    expect(worker.socketPath).toEqual(
+      path.join(socketDir, "tenant-42-id-0-deno-http.sock")
+    );
+    await jsonRequest(worker, "http://localhost/");
+    await worker.close();
+    fs.rmSync(socketDir, { recursive: true });
+
+    const err = await newDenoHTTPWorker(echoScript, {
+      generateSocketId: () => "../escape",
+    }).catch((err) => err);
+    expect(err).toBeInstanceOf(InvalidConfigError);
+  });
+
+  it("rejects socket paths that are too long", async () => {
+    const socketDir = path.join(os.tmpdir(), "d".repeat(100));
+    const err = await newDenoHTTPWorker(echoScript, { socketDir }).catch(
+      (err) => err
+    );
+    expect(err).toBeInstanceOf(SocketPathTooLongError);
+    expect(err.path.startsWith(socketDir)).toEqual(true);
+    expect(err.message).toContain("socketDir");
+
+    // With a fixed ID the exact path that was too long is known.
+    const fixed = await newDenoHTTPWorker(echoScript, {
+      socketDir,
+      generateSocketId: () => "fixed",
+    }).catch((err) => err);
+    expect(fixed).toBeInstanceOf(SocketPathTooLongError);
+    expect(fixed.path).toEqual(path.join(socketDir, "fixed-deno-http.sock"));
+  });
+
+  it("rejects a socketDir that isn't writable", async () => {
+    const socketDir = path.join(os.tmpdir(), "deno-sock-missing");
+    const err = await newDenoHTTPWorker(echoScript, { socketDir }).catch(
+      (err) => err
+    );
+    expect(err).toBeInstanceOf(SocketDirNotWritableError);
+    expect(err.dir).toEqual(socketDir);
+    expect(err.message).toContain("socketDir");
+  });
+
+  it("socketWaitStrategy watch waits for the socket", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      socketWaitStrategy: "watch",
+    });
+    const json = await jsonRequest(worker, "http://localhost/watched");
+    expect(json.url).toEqual("http://localhost/watched");
+    worker.terminate();
+  });
+
+  it("denoSubcommand replaces run", async () => {
+    let args: string[] = [];
+    const worker = await newDenoHTTPWorker(echoScript, {
+      denoSubcommand: ["run", "--no-config"],
+      spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+        args = a;
+        return spawn(command, a, options);
+      },
+    });
+    expect(args.slice(0, 2)).toEqual(["run", "--no-config"]);
+    await jsonRequest(worker, "http://localhost/");
+    worker.terminate();
+  });
+
+  it("waits for a slow to listen bootstrap", async () => {
+    const slowBootstrap = path.resolve(__dirname, "./test/slow-bootstrap.ts");
+    const worker = await newDenoHTTPWorker(echoScript, {
+      denoBootstrapScriptPath: slowBootstrap,
+    });
+    expect(await jsonRequest(worker, "http://localhost/")).toEqual({
+      ok: true,
+    });
+    worker.terminate();
+  });
+
+  it.each([
+    [2, true],
+    [3, false],
+  ])(
+    "warmRequestRetries 2 after %i failed warm requests succeeds: %s",
+    async (failures, succeeds) => {
+      const flaky = path.resolve(__dirname, "./test/flaky-bootstrap.ts");
+      const result = await newDenoHTTPWorker(echoScript, {
+        denoBootstrapScriptPath: flaky,
+        scriptArgs: [String(failures)],
+        warmRequestRetries: 2,
+        warmRequestRetryInterval: 10,
+      }).then(
+        (worker) => worker.terminate(),
+        (err) => err
+      );
+      if (succeeds) {
+        expect(result).toBeUndefined();
+      } else {
+        // The connection was closed without a response.
+        expect(result.code).toEqual("ECONNRESET");
+        expect(result.phase).toEqual("startup");
+      }
+    }
+  );
+
+  it("reports a bootstrap that exits before listening", async () => {
+    const err = await newDenoHTTPWorker(
+      `
+        console.error("giving up");
+        Deno.writeTextFileSync(Deno.args[0], "");
+        await new Promise((resolve) => setTimeout(resolve, 100));
+        Deno.exit(4);
+      `
+    ).catch((err) => err);
+    expect(err.message).toEqual("Deno exited before being ready");
+    expect(err.phase).toEqual("startup");
+    expect(err.code).toEqual(4);
+    expect(err.stderr).toContain("giving up");
+  });
+
+  it("reports an exit right after the socket file appears", async () => {
+    const start = Date.now();
+    const err = await newDenoHTTPWorker(
+      `
+        Deno.writeTextFileSync(Deno.args[0], "");
+        Deno.exit(5);
+      `,
+      { warmConnections: 3 }
+    ).catch((err) => err);
+    expect(err.message).toEqual("Deno exited before being ready");
+    expect(err.code).toEqual(5);
+    expect(Date.now() - start).toBeLessThan(900);
+  });
+
+  it.each([
+    ["export const x = 1;", "No default export"],
+    ["export default { fetch: 1 };", "does not have a fetch function"],
+  ])("rejects a script without a handler: %s", async (script, reason) => {
+    const err = await newDenoHTTPWorker(script).catch((err) => err);
+    expect(err).toBeInstanceOf(InvalidHandlerError);
+    expect(err.reason).toContain(reason);
+  });
+
+  it("reports a bootstrap that fails to compile", async () => {
+    const dir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-http-worker-"));
+    const broken = path.join(dir, "broken-bootstrap.ts");
+    fs.writeFileSync(broken, "const x = {;\n");
+    const err = await newDenoHTTPWorker(echoScript, {
+      denoBootstrapScriptPath: broken,
+    }).catch((err) => err);
+    expect(err).toBeInstanceOf(BootstrapCompileError);
+    expect(err.diagnostics).toContain("broken-bootstrap.ts");
+    fs.rmSync(dir, { recursive: true });
+  });
+
+  it.each([
+    ["mismatched", "0", {}],
+    ["unversioned", "", {}],
+    ["unversioned", "", { warmRequestURL: "https://localhost/health" }],
+  ])(
+    "rejects a %s bootstrap (found %j, %j)",
+    async (name, found, options) => {
+      const bootstrap = path.resolve(__dirname, `./test/${name}-bootstrap.ts`);
+      const err = await newDenoHTTPWorker(echoScript, {
+        denoBootstrapScriptPath: bootstrap,
+        ...options,
+      }).catch((err) => err);
+      expect(err).toBeInstanceOf(ProtocolMismatchError);
+      expect(err.expected).toEqual(1);
+      expect(err.found).toEqual(found);
+    }
+  );
+
+  it("the script's warm response carries the protocol version", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      warmRequestURL: "https://localhost/health",
+    });
+    // The version is only meant for the warm request.
+    const resp = await worker.requestCollect("https://localhost/");
+    expect(resp.headers["x-deno-worker-protocol"]).toBeUndefined();
+    worker.terminate();
+  });
+
+  it("dont crash on socket removal", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          await Deno.removeSync(Deno.args[0]);
+          return Response.json({ ok: req.url })
+        } }
+      `,
+      { printOutput: true }
+    );
+    const json = await jsonRequest(worker, "https://localhost/hello?isee=you", {
+      headers: { accept: "application/json" },
+    });
+    expect(json).toEqual({
+      ok: "https://localhost/hello?isee=you",
+    });
+    worker.terminate();
+  });
+
+  it("reports a removed socket as SocketGoneError", async () => {
+    let socketFile = "";
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      spawnFunc: (command: string, args: string[], options: SpawnOptions) => {
+        socketFile = args[args.length - 4] as string;
+        return spawn(command, args, options);
+      },
+    });
+    fs.rmSync(socketFile);
+    // Concurrent requests need new connections, which can't be established.
+    const results = await Promise.allSettled(
+      [1, 2].map(() => worker.requestCollect("http://localhost/"))
+    );
+    const errors = results
+      .filter((r): r is PromiseRejectedResult => r.status === "rejected")
+      .map((r) => r.reason);
+    expect(errors.length).toBeGreaterThan(0);
+    for (const err of errors) {
+      expect(err).toBeInstanceOf(SocketGoneError);
+      expect(err.code).toEqual("ENOENT");
+    }
+    worker.terminate();
+  });
+
+  it.each([
+    [1, true],
+    [2, false],
+  ])(
+    "connectionFailureThreshold 2 after %i failures reuses: %s",
+    async (failures, reused) => {
+      let socketFile = "";
+      const worker = await newDenoHTTPWorker(
+        `
+          export default { async fetch (req: Request): Promise<Response> {
+            if (new URL(req.url).pathname === "/slow") {
+              await new Promise((resolve) => setTimeout(resolve, 300));
+            }
+            return new Response("ok");
+          }}
+        `,
+        {
+          printOutput: true,
+          connectionFailureThreshold: 2,
+          spawnFunc: (command: string, args: string[], opts: SpawnOptions) => {
+            socketFile = args[args.length - 4] as string;
+            return spawn(command, args, opts);
+          },
+        }
+      );
+      // The slow request takes the pooled connection, so the others need new
+      // ones, which fail while the socket file is moved away.
+      const slow = worker.requestCollect("http://localhost/slow");
+      await new Promise((resolve) => setTimeout(resolve, 50));
+      fs.renameSync(socketFile, socketFile + ".moved");
+      for (let i = 0; i < failures; i++) {
+        const err = await worker
+          .requestCollect("http://localhost/")
+          .catch((err) => err);
+        expect(err).toBeInstanceOf(SocketGoneError);
+      }
+      fs.renameSync(socketFile + ".moved", socketFile);
+
+      // A reset leaves the slow request alone, but doesn't pool its
+      // connection afterwards.
+      expect((await slow).body.toString()).toEqual("ok");
+      const next = await worker.requestWithInfo("http://localhost/");
+      expect(next.info.reusedConnection).toEqual(reused);
+      worker.terminate();
+    }
+  );
+
+  it("rejects a connectionFailureThreshold below 1", async () => {
+    const err = await newDenoHTTPWorker(echoScript, {
+      connectionFailureThreshold: 0,
+    }).catch((err) => err);
+    expect(err).toBeInstanceOf(InvalidConfigError);
+  });
+
+  it("json response multiple requests", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          let headers = {};
+          for (let [key, value] of req.headers.entries()) {
+            headers[key] = value;
+          }
+          return Response.json({ ok: req.url, headers: headers })
+        } }
+      `,
+      { printOutput: true }
+    );
+    for (let i = 0; i < 10; i++) {
+      const json = await jsonRequest(
+        worker,
+        "https://localhost/hello?isee=you",
+        { headers: { accept: "application/json" } }
+      );
+      expect(json).toEqual({
+        ok: "https://localhost/hello?isee=you",
+        headers: { accept: "application/json" },
+      });
+    }
+    worker.terminate();
+  });
+
+  it("onError", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return {} // not a response
+        }, onError (error: Error): Response {
+          return Response.json({ error: error.message }, { status: 500 })
+        }}
+      `,
+      { printOutput: true }
+    );
+    const json = await jsonRequest(worker, "https://localhost/hello?isee=you", {
+      headers: { accept: "application/json" },
+    });
+    expect(json).toEqual({
+      error:
+        "Return value from serve handler must be a response or a promise resolving to a response",
+    });
+
+    worker.terminate();
+  });
+  it("onError not handled", async () => {
+    // onError is not called in all cases, for example, here I can pass a
+    // readable stream and the error is only caught by the global onerror handler.
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const body = new ReadableStream({
+            start(controller) {
+              setTimeout(() => {
+                controller.enqueue(1);
+              }, 10);
+            },
+            cancel() {},
+          });
+          return new Response(body)
+        }, onError (error: Error): Response {
+          return Response.json({ error: error.message }, { status: 500 })
+        }}
+      `,
+      { printOutput: false }
+    );
+    jsonRequest(worker, "https://localhost/hello?isee=you", {
+      headers: { accept: "application/json" },
+    }).catch(() => {});
+
+    for (;;) {
+      const stderr = worker.stderr.read();
+      if (stderr) {
+        console.log(stderr.toString());
+        expect(stderr.toString()).toContain("expected typed ArrayBufferView");
+        break;
+      }
+      await new Promise((resolve) => setTimeout(resolve, 100));
+    }
+    worker.terminate();
+  });
+
+  it("shutdown gracefully", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          new Promise((resolve) => setTimeout(() => {resolve(); console.log("hi")}, 200));
+          return Response.json({ ok: req.url })
+        }}
+      `,
+      { printOutput: true }
+    );
+
+    let logs = "";
+    worker.stderr.on("data", (data) => (logs += data));
+    worker.stdout.on("data", (data) => (logs += data));
+
+    const exitPromise = new Promise<void>((resolve) => {
+      worker.addEventListener("exit", (code) => {
+        expect(code).toEqual(0);
+        expect(logs).toContain("hi");
+        resolve();
+      });
+    });
+    const json = await jsonRequest(worker, "https://localhost/hello?isee=you");
+    expect(json).toEqual({
+      ok: "https://localhost/hello?isee=you",
+    });
+    worker.shutdown();
+    await exitPromise;
+  });
+
+  it("terminate is idempotent", async () => {
+    const worker = await newDenoHTTPWorker(echoScript);
+    let exits = 0;
+    worker.addEventListener("exit", () => exits++);
+    const removed = () => {
+      throw new Error("removed listener was called");
+    };
+    worker.addEventListener("exit", removed);
+    worker.removeEventListener("exit", removed);
+    expect(worker.terminated).toEqual(false);
+    worker.terminate();
+    worker.terminate();
+    await worker.close();
+    expect(worker.terminated).toEqual(true);
+    expect(exits).toEqual(1);
+  });
+
+  it("runToCompletion resolves with how the process ended", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        setTimeout(() => {
+          console.error("all done");
+          Deno.exit(7);
+        }, 50);
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: true })
+        }}
+      `
+    );
+    const result = await worker.runToCompletion();
+    expect(result.code).toEqual(7);
+    expect(result.stderrTail).toContain("all done");
+  });
+
+  it("detach leaves the process running", async () => {
+    const worker = await newDenoHTTPWorker(echoScript);
+    let exited = false;
+    worker.addEventListener("exit", () => (exited = true));
+    const { pid, socketFile } = worker.detach();
+    expect(worker.terminated).toEqual(true);
+    expect(fs.existsSync(socketFile)).toEqual(true);
+    // Signal 0 only checks that the process exists.
+    expect(process.kill(pid!, 0)).toEqual(true);
+    process.kill(pid!, "SIGKILL");
+    await new Promise((resolve) => setTimeout(resolve, 100));
+    expect(exited).toEqual(false);
+    fs.rmSync(socketFile, { force: true });
+  });
+
+  it("a detached process keeps serving while it logs", async () => {
+    const worker = await newDenoHTTPWorker(`
+      export default { async fetch (req: Request): Promise<Response> {
+        // Far more than the pipe and stream buffers hold.
+        for (let i = 0; i < 2000; i++) console.log("x".repeat(100));
+        return new Response("ok")
+      }}
+    `);
+    const { pid, socketFile } = worker.detach();
+    for (let i = 0; i < 3; i++) {
+      const body = await new Promise<string>((resolve, reject) => {
+        const req = http.request(
+          {
+            socketPath: socketFile,
+            path: "/",
+            headers: { "X-Deno-Worker-URL": "https://localhost/" },
+          },
+          (resp) => {
+            const chunks: Buffer[] = [];
+            resp.on("data", (chunk) => chunks.push(chunk));
+            resp.on("end", () => resolve(Buffer.concat(chunks).toString()));
+          }
+        );
+        req.on("error", reject);
+        req.end();
+      });
+      expect(body).toEqual("ok");
+    }
+    process.kill(pid!, "SIGKILL");
+    fs.rmSync(socketFile, { force: true });
+  }, 5000);
+
+  it("close waits for the worker to be fully gone", async () => {
+    let pid: number | undefined;
+    let socketFile = "";
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      spawnFunc: (command: string, args: string[], options: SpawnOptions) => {
+        socketFile = args[args.length - 4] as string;
+        const process = spawn(command, args, options);
+        pid = process.pid;
+        return process;
+      },
+    });
+    await jsonRequest(worker, "http://localhost/");
+    await worker.close();
+    expect(fs.existsSync(socketFile)).toEqual(false);
+    expect(() => process.kill(pid!, 0)).toThrow();
+  });
+
+  it("close stops reading output held open by a subprocess", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        // The subprocess inherits stdout and outlives the worker.
+        new Deno.Command("sleep", { args: ["5"], stdout: "inherit" }).spawn();
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: req.url })
+        }}
+      `,
+      { printOutput: true, runFlags: ["--allow-run=sleep"] }
+    );
+    const start = performance.now();
+    await worker.close();
+    expect(performance.now() - start).toBeLessThan(2000);
+  }, 5000);
+
+  it("shutdown escalates after gracefulShutdownTimeout", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        setInterval(() => {}, 1000);
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: req.url })
+        }}
+      `,
+      { printOutput: true, gracefulShutdownTimeout: 100 }
+    );
+    const exitPromise = new Promise<boolean>((resolve) => {
+      worker.addEventListener("exit", (_code, signal, { forced }) => {
+        expect(signal).toEqual("SIGKILL");
+        resolve(forced);
+      });
+    });
+    worker.shutdown();
+    expect(await exitPromise).toEqual(true);
+  });
+
+  describe("runFlags editing", () => {
+    it.each([
+      "--allow-read",
+      "--allow-write",
+      "--allow-read=/dev/null",
+      "--allow-write=/dev/null",
+      "--allow-read=foo,/dev/null",
+      "--allow-write=bar,/dev/null",
+    ])("should handle %s", async (flag) => {
+      const worker = await newDenoHTTPWorker(echoScript, {
+        printOutput: true,
+        runFlags: [flag],
+      });
+      await jsonRequest(worker, "http://localhost");
+      await worker.terminate();
+    });
+  });
+
+  it("effectiveRunFlags match the flags the process got", async () => {
+    let args: string[] = [];
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      runFlags: ["--allow-env"],
+      allowReadPaths: ["/tmp/readable"],
+      spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+        args = a;
+        return spawn(command, a, options);
+      },
+    });
+    const flags = worker.effectiveRunFlags;
+    expect(args.slice(1, flags.length + 1)).toEqual(flags);
+    expect(flags).toContain("--allow-env");
+    const allowRead = flags.find((flag) => flag.startsWith("--allow-read="));
+    expect(allowRead).toContain("/tmp/readable");
+    worker.terminate();
+  });
+
+  it("resolveWorkerCommand returns the command without spawning", () => {
+    let spawned = false;
+    const resolved = resolveWorkerCommand(echoScript, {
+      runFlags: ["--allow-env"],
+      socketDir: "/tmp",
+      generateSocketId: () => "fixed",
+      denoDir: "/tmp/deno-dir",
+      spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+        spawned = true;
+        return spawn(command, a, options);
+      },
+    });
+    expect(spawned).toEqual(false);
+    expect(resolved.command).toEqual("deno");
+    expect(resolved.args[0]).toEqual("run");
+    expect(resolved.args).toContain("--allow-env");
+    expect(resolved.args).toContain("--no-prompt");
+    const allowRead = resolved.args.find((flag) =>
+      flag.startsWith("--allow-read=")
+    );
+    expect(allowRead).toContain("/tmp/fixed-deno-http.sock");
+    expect(resolved.args.slice(-4)).toEqual([
+      "/tmp/fixed-deno-http.sock",
+      "script",
+      echoScript,
+      "--",
+    ]);
+    expect(resolved.env).toEqual({ DENO_DIR: "/tmp/deno-dir" });
+
+    expect(() =>
+      resolveWorkerCommand(echoScript, { warmConnections: 0 })
+    ).toThrow(InvalidConfigError);
+  });
+
+  it("duplicate and overlapping paths are listed once", async () => {
+    let args: string[] = [];
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      runFlags: ["--allow-read=/tmp/a,/tmp/./a"],
+      allowReadPaths: ["/tmp/a", "/tmp/b/../a", "/tmp/c", "/tmp/c/"],
+      allowWritePaths: ["/tmp/d", "/tmp/d"],
+      spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+        args = a;
+        return spawn(command, a, options);
+      },
+    });
+    const read = args.find((arg) => arg.startsWith("--allow-read="))!;
+    const readPaths = read.slice("--allow-read=".length).split(",");
+    expect(readPaths.filter((p) => p === "/tmp/a")).toHaveLength(1);
+    expect(readPaths.filter((p) => p.startsWith("/tmp/c"))).toHaveLength(1);
+    expect(new Set(readPaths).size).toEqual(readPaths.length);
+    const write = args.find((arg) => arg.startsWith("--allow-write="))!;
+    const writePaths = write.slice("--allow-write=".length).split(",");
+    expect(writePaths.filter((p) => p === "/tmp/d")).toHaveLength(1);
+    await jsonRequest(worker, "http://localhost");
+    await worker.terminate();
+  });
+
+  it("allowReadPaths and allowWritePaths are granted", async () => {
+    const outFile = path.join(os.tmpdir(), `${crypto.randomUUID()}.txt`);
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const { read, write } = await req.json();
+          Deno.writeTextFileSync(write, "written");
+          return Response.json({ read: Deno.readTextFileSync(read).length > 0 })
+        }}
+      `,
+      {
+        printOutput: true,
+        allowReadPaths: [echoFile],
+        allowWritePaths: [outFile],
+      }
+    );
+    const resp = await worker.requestCollect(
+      "http://localhost",
+      { method: "POST" },
+      JSON.stringify({ read: echoFile, write: outFile })
+    );
+    expect(JSON.parse(resp.body.toString())).toEqual({ read: true });
+    expect(fs.readFileSync(outFile, { encoding: "utf-8" })).toEqual("written");
+    fs.rmSync(outFile);
+    worker.terminate();
+  });
+
+  it("missing permissions fail with PermissionDeniedError", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return new Response(Deno.readTextFileSync("/etc/hosts"))
+        }}
+      `
+    );
+    const err = await worker
+      .requestCollect("https://localhost/")
+      .catch((err) => err);
+    expect(err).toBeInstanceOf(PermissionDeniedError);
+    expect(err.message).toContain("/etc/hosts");
+    worker.terminate();
+  });
+
+  it("scripts over maxArgvScriptBytes are passed on stdin", async () => {
+    let args: string[] = [];
+    const script = `// ${"x".repeat(200 * 1024)}\n${echoScript}`;
+    const worker = await newDenoHTTPWorker(script, {
+      printOutput: true,
+      spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+        args = a;
+        return spawn(command, a, options);
+      },
+    });
+    expect(args).toContain("stdin");
+    expect(args.join(" ")).not.toContain("xxxx");
+    const json = await jsonRequest(worker, "http://localhost/large");
+    expect(json.url).toEqual("http://localhost/large");
+    worker.terminate();
+  });
+
+  it("scriptTransport stdin keeps the script out of argv", async () => {
+    let args: string[] = [];
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      scriptTransport: "stdin",
+      spawnFunc: (command: string, a: string[], options: SpawnOptions) => {
+        args = a;
+        return spawn(command, a, options);
+      },
+    });
+    expect(args.join(" ")).not.toContain("req.headers.entries()");
+    const json = await jsonRequest(worker, "http://localhost/stdin");
+    expect(json.url).toEqual("http://localhost/stdin");
+    worker.terminate();
+  });
+
+  it("should be able to import script", async () => {
+    const file = path.resolve(__dirname, "./test/echo-request.ts");
+    const url = new URL(`file://${file}`);
+    const worker = await newDenoHTTPWorker(url, {
+      printOutput: true,
+      printCommandAndArguments: true,
+    });
+
+    await jsonRequest(worker, "http://localhost");
+    await worker.terminate();
+  });
+
+  it("allowReadPaths grants directory trees to imported scripts", async () => {
+    const dataDir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-data-"));
+    fs.mkdirSync(path.join(dataDir, "nested"));
+    fs.writeFileSync(path.join(dataDir, "nested", "data.txt"), "data");
+    const scriptFile = path.join(dataDir, "..", `${crypto.randomUUID()}.ts`);
+    fs.writeFileSync(
+      scriptFile,
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return new Response(Deno.readTextFileSync(${JSON.stringify(
+            path.join(dataDir, "nested", "data.txt")
+          )}))
+        }}
+      `
+    );
+    const worker = await newDenoHTTPWorker(new URL(`file://${scriptFile}`), {
+      printOutput: true,
+      allowReadPaths: [dataDir],
+    });
+    const resp = await worker.requestCollect("http://localhost");
+    expect(resp.body.toString()).toEqual("data");
+    await worker.close();
+    fs.rmSync(scriptFile);
+    fs.rmSync(dataDir, { recursive: true });
+  });
+
+  it("watch restarts the script when the file changes", async () => {
+    const file = path.join(os.tmpdir(), `${crypto.randomUUID()}-watch.ts`);
+    const writeScript = (version: number) =>
+      fs.writeFileSync(
+        file,
+        `export default { fetch: () => Response.json({ version: ${version} }) }`
+      );
+    writeScript(1);
+    const worker = await newDenoHTTPWorker(new URL(`file://${file}`), {
+      printOutput: true,
+      watch: true,
+    });
+    expect(await jsonRequest(worker, "http://localhost")).toEqual({
+      version: 1,
+    });
+
+    writeScript(2);
+    for (;;) {
+      const json = await jsonRequest(worker, "http://localhost").catch(
+        () => undefined
+      );
+      if (json?.version === 2) {
+        break;
+      }
+      await new Promise((resolve) => setTimeout(resolve, 50));
+    }
+    worker.terminate();
+    fs.rmSync(file);
+  }, 10000);
+
+  it("requestWithHeaderList preserves order and duplicates", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json([...req.headers.entries()])
+        }}
+      `,
+      { printOutput: true }
+    );
+    const json = await new Promise((resolve, reject) => {
+      const req = worker.requestWithHeaderList(
+        "https://localhost/",
+        [
+          ["x-b", "1"],
+          ["x-a", "2"],
+          ["x-b", "3"],
+          ["host", "fish"],
+        ],
+        {},
+        (resp) => {
+          const body: any[] = [];
+          resp.on("data", (chunk) => body.push(chunk));
+          resp.on("end", () => {
+            resolve(JSON.parse(Buffer.concat(body).toString()));
+          });
+        }
+      );
+      req.on("error", reject);
+      req.end();
+    });
+    // Deno's Headers sort names and join repeated values.
+    expect(json).toEqual([
+      ["host", "fish"],
+      ["x-a", "2"],
+      ["x-b", "1, 3"],
+    ]);
+    worker.terminate();
+  });
+
+  it("requestInterceptor can rewrite every request", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      requestInterceptor: (request) => {
+        request.url.pathname = "/intercepted" + request.url.pathname;
+        (request.options.headers as any)["x-trace"] = "abc";
+      },
+    });
+    const json = await jsonRequest(worker, "https://localhost/path");
+    expect(json.url).toEqual("https://localhost/intercepted/path");
+    expect(json.headers["x-trace"]).toEqual("abc");
+
+    const collected = await worker.requestCollect("https://localhost/");
+    expect(JSON.parse(collected.body.toString()).headers["x-trace"]).toEqual(
+      "abc"
+    );
+    worker.terminate();
+  });
+
+  it("defaultHeaders are sent unless a request sets them", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      defaultHeaders: { authorization: "Bearer default", "x-api": "2" },
+    });
+    const json = await jsonRequest(worker, "https://localhost/", {
+      headers: { Authorization: "Bearer call" },
+    });
+    // Repeated headers would arrive joined with a comma.
+    expect(json.headers.authorization).toEqual("Bearer call");
+    expect(json.headers["x-api"]).toEqual("2");
+    worker.terminate();
+  });
+
+  it("withForwardedFor appends the client to the chain", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const json = await jsonRequest(worker, "https://localhost/", {
+      headers: withForwardedFor(
+        { "X-Forwarded-For": "10.0.0.1", accept: "text/plain" },
+        "2001:db8::1"
+      ) as Record<string, string>,
+    });
+    expect(json.headers["x-forwarded-for"]).toEqual("10.0.0.1, 2001:db8::1");
+    expect(json.headers.forwarded).toEqual('for="[2001:db8::1]"');
+    expect(json.headers.accept).toEqual("text/plain");
+    worker.terminate();
+  });
+
+  it("traceContext is sent with every request", async () => {
+    const traceId = "0af7651916cd43dd8448eb211c80319c";
+    let span = 1;
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      traceContext: () => ({
+        traceparent: `00-${traceId}-${String(span++).padStart(16, "0")}-01`,
+        tracestate: "vendor=1",
+      }),
+    });
+    const first = await jsonRequest(worker, "https://localhost/");
+    expect(first.headers.traceparent).toMatch(/-0000000000000001-01$/);
+    expect(first.headers.tracestate).toEqual("vendor=1");
+    const second = await jsonRequest(worker, "https://localhost/");
+    expect(second.headers.traceparent).toMatch(/-0000000000000002-01$/);
+    worker.terminate();
+  });
+
+  it("responseInterceptor sees every response", async () => {
+    const seen: [string, number | undefined][] = [];
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return new Response("ok", { headers: { "x-internal": "1" } })
+        }}
+      `,
+      {
+        printOutput: true,
+        responseInterceptor: (response, request) => {
+          seen.push([request.url.href, response.statusCode]);
+          delete response.headers["x-internal"];
+        },
+      }
+    );
+    const resp = await worker.requestCollect("https://localhost/a");
+    expect(resp.body.toString()).toEqual("ok");
+    expect(resp.headers["x-internal"]).toBeUndefined();
+    expect(seen).toEqual([["https://localhost/a", 200]]);
+    worker.terminate();
+  });
+
+  it("responseHeaderAllowlist removes other headers", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return new Response("ok", {
+            headers: { "x-internal": "1", "Content-Type": "text/plain" },
+          })
+        }}
+      `,
+      { printOutput: true, responseHeaderAllowlist: ["content-type"] }
+    );
+    const resp = await worker.requestCollect("https://localhost/");
+    expect(resp.headers).toEqual({ "content-type": "text/plain" });
+    const rawHeaders = await new Promise<string[]>((resolve, reject) => {
+      const req = worker.request("https://localhost/", {}, (resp) => {
+        resp.resume();
+        resolve(resp.rawHeaders);
+      });
+      req.on("error", reject);
+      req.end();
+    });
+    expect(rawHeaders.map((h) => h.toLowerCase())).toEqual([
+      "content-type",
+      "text/plain",
+    ]);
+    worker.terminate();
+  });
+
+  it("preloadModules are imported before the worker is ready", async () => {
+    const dir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-http-worker-"));
+    const lazy = path.join(dir, "lazy.ts");
+    fs.writeFileSync(lazy, `console.log("lazy loaded"); export const x = 1;`);
+    fs.writeFileSync(
+      path.join(dir, "main.ts"),
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const { x } = await import("./lazy.ts");
+          return Response.json(x)
+        }}
+      `
+    );
+    const worker = await newDenoHTTPWorkerFromPath(path.join(dir, "main.ts"), {
+      printOutput: true,
+      preloadModules: [pathToFileURL(lazy)],
+    });
+    const lines = () => worker.capturedLogs().map(({ line }) => line);
+    expect(lines()).toContain("lazy loaded");
+    const resp = await worker.requestCollect("https://localhost/");
+    expect(JSON.parse(resp.body.toString())).toEqual(1);
+    // The module was evaluated once, by the preload.
+    expect(lines().filter((line) => line === "lazy loaded")).toHaveLength(1);
+    worker.terminate();
+    fs.rmSync(dir, { recursive: true });
+  });
+
+  it("should be able to run a script from bytes", async () => {
+    const bom = Buffer.from([0xef, 0xbb, 0xbf]);
+    const source = Buffer.from(`
+      export default { async fetch (req: Request): Promise<Response> {
+        return new Response("from bytes")
+      }}
+    `);
+    const worker = await newDenoHTTPWorkerFromBytes(
+      Buffer.concat([bom, source]),
+      { printOutput: true }
+    );
+    const resp = await worker.requestCollect("https://localhost/");
+    expect(resp.body.toString()).toEqual("from bytes");
+    const exited = new Promise((resolve) =>
+      worker.addEventListener("exit", resolve)
+    );
+    const [scriptPath] = worker.effectiveRunFlags
+      .find((flag) => flag.startsWith("--allow-read="))!
+      .slice("--allow-read=".length)
+      .split(",")
+      .filter((p) => p.endsWith("script.tsx"));
+    expect(fs.existsSync(scriptPath!)).toBe(true);
+    worker.terminate();
+    await exited;
+    await new Promise((resolve) => setTimeout(resolve, 50));
+    expect(fs.existsSync(scriptPath!)).toBe(false);
+  });
+
+  it("should be able to run a script from a path", async () => {
+    const dir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-http-worker-"));
+    fs.writeFileSync(
+      path.join(dir, "message.ts"),
+      `export const message = "from a sibling";`
+    );
+    fs.writeFileSync(
+      path.join(dir, "main.ts"),
+      `
+        import { message } from "./message.ts";
+        export default { fetch: () => Response.json({ message }) };
+      `
+    );
+    const worker = await newDenoHTTPWorkerFromPath(path.join(dir, "main.ts"), {
+      printOutput: true,
+    });
+    expect(await jsonRequest(worker, "http://localhost")).toEqual({
+      message: "from a sibling",
+    });
+    worker.terminate();
+    fs.rmSync(dir, { recursive: true });
+  });
+
+  it.each(["/app", "app/", "//app//"])(
+    "basePath %s prefixes request paths",
+    async (basePath) => {
+      const worker = await newDenoHTTPWorker(echoScript, { basePath });
+      const json = await jsonRequest(worker, "https://localhost/users?id=1");
+      expect(json.url).toEqual("https://localhost/app/users?id=1");
+      const root = await jsonRequest(worker, "https://localhost/");
+      expect(root.url).toEqual("https://localhost/app/");
+      worker.terminate();
+    }
+  );
+
+  it("host and connection is not overwritten", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+    });
+    const resp: any = await jsonRequest(worker, "https://localhost/", {
       headers: { connection: "happy", host: "fish" },
     });
-    expect(resp["headers"]["connection"]).toEqual("happy");
-    expect(resp["headers"]["host"]).toEqual("fish");
-    await worker.terminate();
+    expect(resp["headers"]["connection"]).toEqual("happy");
+    expect(resp["headers"]["host"]).toEqual("fish");
+    await worker.terminate();
+  });
+
+  it("preserveHost sends the host of the request URL", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      preserveHost: true,
+    });
+    const resp: any = await jsonRequest(worker, "https://tenant.example:8443/");
+    expect(resp["headers"]["host"]).toEqual("tenant.example:8443");
+    const explicit: any = await jsonRequest(worker, "https://localhost/", {
+      headers: { host: "fish" },
+    });
+    expect(explicit["headers"]["host"]).toEqual("fish");
+    worker.terminate();
+  });
+
+  // it("json response", async () => {
+  //   let worker = await newDenoHTTPWorker(echoScript);
+
+  //   const t0 = performance.now();
+  //   let resp = (await worker.client
+  //     .post("https://localhost/", { json: { ok: true } })
+  //     .json()) as any;
+  //   expect(resp.body).toEqual('{"ok":true}');
+  //   console.log("Got time", performance.now() - t0);
+
+  //   // TODO: test against streaming resp as well as request body
+  //   let req = worker.client.post("https://localhost/", {
+  //     body: fs.createReadStream(import.meta.url.replace("file://", "")),
+  //   });
+
+  //   let body: any = await req.json();
+  //   expect(body.body).toEqual(
+  //     fs.readFileSync(import.meta.url.replace("file://", "")).toString()
+  //   );
+
+  //   worker.terminate();
+  // });
+
+  it("use http directly", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+
+    await new Promise((resolve) => setTimeout(resolve, 300));
+
+    const t0 = performance.now();
+    const json = await new Promise((resolve) => {
+      const req = worker.request("http://localhost/hi", {}, (resp) => {
+        const body: any[] = [];
+        resp.on("data", (chunk) => {
+          body.push(chunk);
+        });
+        resp.on("end", () => {
+          resolve(JSON.parse(Buffer.concat(body).toString()));
+        });
+      });
+      req.end();
+    });
+    console.log("http request time", performance.now() - t0);
+    expect(json).toEqual({
+      url: "http://localhost/hi",
+      headers: {},
+      body: "",
+      method: "GET",
+    });
+    worker.terminate();
+  });
+
+  it("requestCollect buffers the full response", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const resp = await worker.requestCollect(
+      "http://localhost/collect",
+      { method: "POST" },
+      "hello"
+    );
+    expect(resp.statusCode).toEqual(200);
+    expect(resp.headers["content-type"]).toEqual("application/json");
+    expect(JSON.parse(resp.body.toString())).toEqual({
+      url: "http://localhost/collect",
+      headers: { "content-length": "5" },
+      body: "hello",
+      method: "POST",
+    });
+    worker.terminate();
+  });
+
+  it("requestJSON returns the status with the parsed body", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          if (new URL(req.url).pathname === "/text") return new Response("no");
+          return Response.json({ error: "missing" }, { status: 404 })
+        }}
+      `,
+      { printOutput: true }
+    );
+    const resp = await worker.requestJSON("https://localhost/");
+    expect(resp.statusCode).toEqual(404);
+    expect(resp.body).toEqual({ error: "missing" });
+
+    const err = await worker
+      .requestJSON("https://localhost/text")
+      .catch((err) => err);
+    expect(err).toBeInstanceOf(ResponseDecodeError);
+    expect(err.statusCode).toEqual(200);
+    expect(err.body.toString()).toEqual("no");
+    worker.terminate();
+  });
+
+  it("requestText decodes the body with the declared charset", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const charset = new URL(req.url).searchParams.get("charset");
+          return new Response(new Uint8Array([0x63, 0x61, 0x66, 0xe9]), {
+            headers: charset
+              ? { "content-type": "text/html; charset=" + charset }
+              : {},
+          });
+        }}
+      `,
+      { printOutput: true }
+    );
+    const resp = await worker.requestText(
+      "https://localhost/?charset=iso-8859-1"
+    );
+    expect(resp.statusCode).toEqual(200);
+    expect(resp.body).toEqual("café");
+
+    // 0xe9 on its own is not valid UTF-8, the default.
+    for (const url of ["https://localhost/", "https://localhost/?charset=x"]) {
+      const err = await worker.requestText(url).catch((err) => err);
+      expect(err).toBeInstanceOf(ResponseDecodeError);
+      expect(err.body).toEqual(Buffer.from([0x63, 0x61, 0x66, 0xe9]));
+    }
+    worker.terminate();
+  });
+
+  it("requestJSONValue sends a JSON body", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const resp = await worker.requestJSONValue("https://localhost/", {
+      hello: ["world"],
+    });
+    expect(resp.statusCode).toEqual(200);
+    expect(resp.body.method).toEqual("POST");
+    expect(resp.body.headers["content-type"]).toEqual("application/json");
+    expect(JSON.parse(resp.body.body)).toEqual({ hello: ["world"] });
+    worker.terminate();
+  });
+
+  it("requestNDJSON yields records as they arrive", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const encoder = new TextEncoder();
+          const body = new ReadableStream({
+            async start(controller) {
+              for (let i = 0; i < 3; i++) {
+                controller.enqueue(encoder.encode(JSON.stringify({ i }) + "\\n"));
+                await new Promise((resolve) => setTimeout(resolve, 10));
+              }
+              controller.enqueue(encoder.encode('{"last"'));
+              controller.enqueue(encoder.encode(': true}'));
+              controller.close();
+            },
+          });
+          return new Response(body)
+        }}
+      `,
+      { printOutput: true }
+    );
+    const records = [];
+    for await (const record of worker.requestNDJSON("https://localhost/")) {
+      records.push(record);
+    }
+    expect(records).toEqual([{ i: 0 }, { i: 1 }, { i: 2 }, { last: true }]);
+    worker.terminate();
+  });
+
+  it("requestWithRetry retries server errors", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        let attempts = 0;
+        export default { async fetch (req: Request): Promise<Response> {
+          const body = await req.text();
+          if (++attempts < 3) return new Response(body, { status: 503 });
+          return Response.json({ attempts, body })
+        }}
+      `,
+      { printOutput: true }
+    );
+    const resp = await worker.requestWithRetry(
+      "https://localhost/",
+      { method: "POST" },
+      "replayed",
+      { maxAttempts: 3, backoff: 1 }
+    );
+    expect(resp.statusCode).toEqual(200);
+    expect(JSON.parse(resp.body.toString())).toEqual({
+      attempts: 3,
+      body: "replayed",
+    });
+
+    const exhausted = await worker.requestWithRetry(
+      "https://localhost/",
+      {},
+      undefined,
+      { maxAttempts: 1, retryStatuses: [200] }
+    );
+    expect(exhausted.statusCode).toEqual(200);
+    worker.terminate();
+  });
+
+  it("statusErrors turns statuses into errors", async () => {
+    class UpstreamTimeoutError extends Error {}
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return new Response("upstream timed out", { status: 504 })
+        }}
+      `,
+      {
+        printOutput: true,
+        statusErrors: {
+          504: (resp) => new UpstreamTimeoutError(resp.body.toString()),
+        },
+      }
+    );
+    const err = await worker
+      .requestJSON("https://localhost/")
+      .catch((err) => err);
+    expect(err).toBeInstanceOf(UpstreamTimeoutError);
+    expect(err.message).toEqual("upstream timed out");
+    worker.terminate();
+  });
+
+  it("statusErrors mappers that throw reject the request", async () => {
+    const thrown = new Error("mapper failed");
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return new Response("gone", { status: 410 })
+        }}
+      `,
+      {
+        printOutput: true,
+        statusErrors: {
+          410: () => {
+            throw thrown;
+          },
+        },
+      }
+    );
+    const err = await worker
+      .requestCollect("https://localhost/")
+      .catch((err) => err);
+    expect(err).toBe(thrown);
+    worker.terminate();
+  });
+
+  it("aborting a request aborts the handler", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        let aborted = false;
+        export default { async fetch (req: Request): Promise<Response> {
+          if (new URL(req.url).pathname === "/status") {
+            return Response.json({ aborted });
+          }
+          req.signal.addEventListener("abort", () => (aborted = true));
+          await new Promise((resolve) => setTimeout(resolve, 500));
+          return new Response("too late");
+        }}
+      `,
+      { printOutput: true }
+    );
+    const controller = new AbortController();
+    const resp = worker.requestCollect("https://localhost/slow", {
+      signal: controller.signal,
+    });
+    setTimeout(() => controller.abort(), 50);
+    await expect(resp).rejects.toHaveProperty("name", "AbortError");
+
+    for (;;) {
+      const json = await jsonRequest(worker, "https://localhost/status");
+      if (json.aborted) break;
+      await new Promise((resolve) => setTimeout(resolve, 20));
+    }
+    worker.terminate();
   });
 
-  // it("json response", async () => {
-  //   let worker = await newDenoHTTPWorker(echoScript);
+  it("response headers are available before the body", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const body = new ReadableStream({
+            async start(controller) {
+              await new Promise((resolve) => setTimeout(resolve, 300));
+              controller.enqueue(new TextEncoder().encode("late"));
+              controller.close();
+            },
+          });
+          return new Response(body, { headers: { "content-type": "x/late" } });
+        }}
+      `,
+      { printOutput: true }
+    );
+    const start = Date.now();
+    const resp = await new Promise<http.IncomingMessage>((resolve, reject) => {
+      const req = worker.request("https://localhost/", {}, resolve);
+      req.on("error", reject);
+      req.end();
+    });
+    expect(resp.headers["content-type"]).toEqual("x/late");
+    expect(Date.now() - start).toBeLessThan(300);
+    const chunks: Buffer[] = [];
+    for await (const chunk of resp) chunks.push(chunk);
+    expect(Buffer.concat(chunks).toString()).toEqual("late");
+    worker.terminate();
+  });
 
-  //   const t0 = performance.now();
-  //   let resp = (await worker.client
-  //     .post("https://localhost/", { json: { ok: true } })
-  //     .json()) as any;
-  //   expect(resp.body).toEqual('{"ok":true}');
-  //   console.log("Got time", performance.now() - t0);
+  it("destroying a request aborts the handler", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        let aborted = false;
+        export default { async fetch (req: Request): Promise<Response> {
+          if (new URL(req.url).pathname === "/status") {
+            return Response.json({ aborted });
+          }
+          req.signal.addEventListener("abort", () => (aborted = true));
+          await new Promise((resolve) => setTimeout(resolve, 500));
+          return new Response("too late");
+        }}
+      `,
+      { printOutput: true }
+    );
+    const req = worker.request("https://localhost/slow", {}, () => {});
+    req.on("error", () => {});
+    req.end();
+    setTimeout(() => req.destroy(), 50);
 
-  //   // TODO: test against streaming resp as well as request body
-  //   let req = worker.client.post("https://localhost/", {
-  //     body: fs.createReadStream(import.meta.url.replace("file://", "")),
-  //   });
+    for (;;) {
+      const json = await jsonRequest(worker, "https://localhost/status");
+      if (json.aborted) break;
+      await new Promise((resolve) => setTimeout(resolve, 20));
+    }
+    worker.terminate();
+  });
 
-  //   let body: any = await req.json();
-  //   expect(body.body).toEqual(
-  //     fs.readFileSync(import.meta.url.replace("file://", "")).toString()
-  //   );
+  it("rejects invalid request URLs", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    expect(() => worker.request("/no-host", {}, () => {})).toThrow(
+      InvalidUrlError
+    );
+    await expect(worker.requestCollect("not a url")).rejects.toBeInstanceOf(
+      InvalidUrlError
+    );
+    expect(worker.inFlight).toEqual(0);
+    worker.terminate();
+  });
 
-  //   worker.terminate();
-  // });
+  it("test-util echoes chunks one at a time", async () => {
+    const worker = await newStreamingEchoWorker();
+    const chunks = await echoChunksIncrementally(worker, ["a", "bc", "def"]);
+    expect(chunks.map((chunk) => chunk.toString())).toEqual(["a", "bc", "def"]);
+    worker.terminate();
+  });
 
-  it("use http directly", async () => {
+  it("test-util sees backpressure from a stalled reader", async () => {
+    const worker = await newDenoHTTPWorker(STALLED_READER_SCRIPT);
+    expect(await writeUntilBackpressure(worker)).toBeGreaterThan(0);
+    worker.terminate();
+  }, 3000);
+
+  it("test-util rejects when the connection fails", async () => {
+    const worker = await newDenoHTTPWorker(STALLED_READER_SCRIPT);
+    fs.rmSync(worker.socketPath);
+    // Without a pooled connection the request has to open a new one.
+    worker.resetConnections();
+    const err = await writeUntilBackpressure(worker).catch((err) => err);
+    expect(err).toBeInstanceOf(SocketGoneError);
+    worker.terminate();
+  });
+
+  it.each([
+    ["shared", [1, 2]],
+    ["perRequest", [1, 1]],
+  ] as const)("isolation %s", async (isolation, counts) => {
+    const worker = await newDenoHTTPWorker(
+      `
+        let count = 0;
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json(++count);
+        }}
+      `,
+      { printOutput: true, isolation }
+    );
+    const seen: number[] = [];
+    for (let i = 0; i < 2; i++) {
+      const resp = await worker.requestCollect("https://localhost/");
+      seen.push(JSON.parse(resp.body.toString()));
+    }
+    expect(seen).toEqual(counts);
+    worker.terminate();
+  });
+
+  it.each([
+    ["PUT", JSON.stringify({ a: 1 })],
+    ["PATCH", JSON.stringify({ a: 1 })],
+    ["DELETE", JSON.stringify({ a: 1 })],
+    ["PUT", ""],
+    ["PATCH", ""],
+    ["DELETE", ""],
+  ])("%s requests deliver the body %j", async (method, body) => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const resp = await worker.requestCollect(
+      "https://localhost/",
+      { method, headers: { "content-type": "application/json" } },
+      body
+    );
+    const json = JSON.parse(resp.body.toString());
+    expect(json.method).toEqual(method);
+    expect(json.body).toEqual(body);
+    // The connection is still usable afterwards.
+    expect((await jsonRequest(worker, "https://localhost/next")).url).toEqual(
+      "https://localhost/next"
+    );
+    worker.terminate();
+  });
+
+  it.each(["DELETE", "OPTIONS"])(
+    "%s requests with a header list deliver the body",
+    async (method) => {
+      const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+      const json = await new Promise<any>((resolve, reject) => {
+        const req = worker.requestWithHeaderList(
+          "https://localhost/",
+          [["content-type", "application/json"]],
+          { method },
+          (res) => {
+            const body: Buffer[] = [];
+            res.on("data", (chunk) => body.push(chunk));
+            res.on("end", () => resolve(JSON.parse(Buffer.concat(body))));
+          }
+        );
+        req.on("error", reject);
+        req.end(JSON.stringify({ a: 1 }));
+      });
+      expect(json.method).toEqual(method);
+      expect(json.body).toEqual(JSON.stringify({ a: 1 }));
+      worker.terminate();
+    }
+  );
+
+  it("requestBatch returns results in order", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        let active = 0;
+        let maxActive = 0;
+        export default { async fetch (req: Request): Promise<Response> {
+          const { pathname } = new URL(req.url);
+          if (pathname === "/max") return Response.json(maxActive);
+          if (pathname === "/fail") throw new Error("failed");
+          active++;
+          maxActive = Math.max(maxActive, active);
+          await new Promise((resolve) => setTimeout(resolve, 20));
+          active--;
+          return new Response(pathname);
+        }}
+      `,
+      { printOutput: true }
+    );
+    const results = await worker.requestBatch(
+      [
+        { url: "https://localhost/a" },
+        { url: "https://localhost/fail" },
+        { url: "https://localhost/b", options: { method: "POST" }, body: "x" },
+        { url: "https://localhost/c" },
+      ],
+      2
+    );
+    expect(
+      results.map((result) =>
+        result.status === "fulfilled"
+          ? [result.value.statusCode, result.value.body.toString()]
+          : result.status
+      )
+    ).toEqual([
+      [200, "/a"],
+      [500, "Internal Server Error"],
+      [200, "/b"],
+      [200, "/c"],
+    ]);
+    const max = await worker.requestCollect("https://localhost/max");
+    expect(JSON.parse(max.body.toString())).toBeLessThanOrEqual(2);
+    worker.terminate();
+  });
+
+  it("requestCollect streams iterable bodies", async () => {
     const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const resp = await worker.requestCollect(
+      "http://localhost/stream",
+      { method: "POST" },
+      Readable.from(["hello", " ", "world"])
+    );
+    expect(JSON.parse(resp.body.toString())["body"]).toEqual("hello world");
 
-    await new Promise((resolve) => setTimeout(resolve, 300));
+    async function* chunks() {
+      yield Buffer.from("async ");
+      yield Buffer.from("chunks");
+    }
+    const resp2 = await worker.requestCollect(
+      "http://localhost/stream",
+      { method: "POST" },
+      chunks()
+    );
+    expect(JSON.parse(resp2.body.toString())["body"]).toEqual("async chunks");
 
-    const t0 = performance.now();
-    const json = await new Promise((resolve) => {
-      const req = worker.request("http://localhost/hi", {}, (resp) => {
-        const body: any[] = [];
-        resp.on("data", (chunk) => {
-          body.push(chunk);
-        });
-        resp.on("end", () => {
-          resolve(JSON.parse(Buffer.concat(body).toString()));
+    // Files are streamed from disk rather than read into memory first.
+    const resp3 = await worker.requestCollect(
+      "http://localhost/stream",
+      { method: "POST" },
+      fs.createReadStream(echoFile)
+    );
+    expect(JSON.parse(resp3.body.toString())["body"]).toEqual(echoScript);
+    worker.terminate();
+  });
+
+  it("maxResponseBytes limits collected responses", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const size = Number(new URL(req.url).searchParams.get("size"));
+          return new Response("x".repeat(size));
+        }}
+      `,
+      { printOutput: true, maxResponseBytes: 1024 }
+    );
+    const small = await worker.requestCollect("https://localhost/?size=1024");
+    expect(small.body.length).toEqual(1024);
+    const err = await worker
+      .requestCollect("https://localhost/?size=100000")
+      .catch((err) => err);
+    expect(err).toBeInstanceOf(ResponseTooLargeError);
+    expect(err.limit).toEqual(1024);
+    worker.terminate();
+  });
+
+  it("requestWithInfo reports connection reuse", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    // The warm request leaves a pooled connection behind.
+    const first = await worker.requestWithInfo("http://localhost/");
+    expect(first.response.statusCode).toEqual(200);
+    expect(first.info.reusedConnection).toEqual(true);
+    expect(first.info.connectTime).toEqual(0);
+    expect(first.info.timeToFirstByte).toBeGreaterThan(0);
+
+    // A second concurrent request needs a new connection.
+    const [, second] = await Promise.all([
+      worker.requestWithInfo("http://localhost/"),
+      worker.requestWithInfo("http://localhost/"),
+    ]);
+    expect(second?.info.reusedConnection).toEqual(false);
+    expect(second?.info.connectTime).toBeGreaterThan(0);
+    worker.terminate();
+  });
+
+  it("requestTimeout sends the deadline and aborts slow requests", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          if (new URL(req.url).pathname === "/slow") {
+            await new Promise(() => {});
+          }
+          return new Response(req.headers.get("X-Deno-Worker-Deadline-Ms"));
+        }}
+      `,
+      { printOutput: true, requestTimeout: 200 }
+    );
+    const resp = await worker.requestCollect("https://localhost/", {
+      headers: { "X-Deno-Worker-Deadline-Ms": "1" },
+    });
+    expect(resp.body.toString()).toEqual("200");
+
+    const err = await worker
+      .requestCollect("https://localhost/slow")
+      .catch((err) => err);
+    expect(err).toBeInstanceOf(RequestTimeoutError);
+    expect(err.timeout).toEqual(200);
+    worker.terminate();
+  });
+
+  it("resetConnections closes pooled connections", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, { printOutput: true });
+    const first = await worker.requestWithInfo("http://localhost/");
+    expect(first.info.reusedConnection).toEqual(true);
+
+    worker.resetConnections();
+    const second = await worker.requestWithInfo("http://localhost/");
+    expect(second.response.statusCode).toEqual(200);
+    expect(second.info.reusedConnection).toEqual(false);
+    const third = await worker.requestWithInfo("http://localhost/");
+    expect(third.info.reusedConnection).toEqual(true);
+    worker.terminate();
+  });
+
+  it("tracks in-flight requests", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          await new Promise((resolve) => setTimeout(resolve, 100));
+          return Response.json({ ok: req.url })
+        }}
+      `,
+      { printOutput: true }
+    );
+    expect(worker.inFlight).toEqual(0);
+    const requests = [
+      jsonRequest(worker, "https://localhost/1"),
+      jsonRequest(worker, "https://localhost/2"),
+    ];
+    expect(worker.inFlight).toEqual(2);
+    await worker.waitIdle();
+    expect(worker.inFlight).toEqual(0);
+    await Promise.all(requests);
+    worker.terminate();
+  });
+
+  it("stdoutFile and stderrFile append output to files", async () => {
+    const dir = fs.mkdtempSync(path.join(os.tmpdir(), "deno-logs-"));
+    const stdoutFile = path.join(dir, "stdout.log");
+    const stderrFile = path.join(dir, "stderr.log");
+    fs.writeFileSync(stdoutFile, "earlier\n");
+    const worker = await newDenoHTTPWorker(
+      `
+        console.log("to stdout");
+        console.error("to stderr");
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: true })
+        }}
+      `,
+      { stdoutFile, stderrFile }
+    );
+    await worker.close();
+    expect(fs.readFileSync(stdoutFile, { encoding: "utf-8" })).toEqual(
+      "earlier\nto stdout\n"
+    );
+    expect(fs.readFileSync(stderrFile, { encoding: "utf-8" })).toContain(
+      "to stderr\n"
+    );
+    fs.rmSync(dir, { recursive: true });
+  });
+
+  it("replace swaps the process without interrupting requests", async () => {
+    const script = (version: number) => `
+      export default { async fetch (req: Request): Promise<Response> {
+        await new Promise((resolve) => setTimeout(resolve, 100));
+        return Response.json({ version })
+      }}
+    `;
+    const worker = await newDenoHTTPWorker(script(1), { printOutput: true });
+    let exits = 0;
+    worker.addEventListener("exit", () => exits++);
+    const oldPid = worker.withProcess((p) => p.pid);
+
+    const inFlight = jsonRequest(worker, "https://localhost/");
+    await worker.replace(script(2), { printOutput: true });
+    expect(await inFlight).toEqual({ version: 1 });
+    expect(await jsonRequest(worker, "https://localhost/")).toEqual({
+      version: 2,
+    });
+    expect(worker.withProcess((p) => p.pid)).not.toEqual(oldPid);
+    expect(exits).toEqual(0);
+
+    await worker.close();
+    expect(exits).toEqual(1);
+  }, 3000);
+
+  it("replace keeps the options that aren't overridden", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      defaultHeaders: { "x-kept": "yes" },
+      basePath: "/before",
+    });
+    await worker.replace(echoScript, { basePath: "/after" });
+    const json = await jsonRequest(worker, "https://localhost/x");
+    expect(json.headers["x-kept"]).toEqual("yes");
+    expect(json.url).toEqual("https://localhost/after/x");
+    await worker.close();
+  }, 3000);
+
+  it("controlSocket handles reload, stats and drain", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        let count = 0;
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ count: ++count })
+        }}
+      `,
+      { printOutput: true, controlSocket: true }
+    );
+    await jsonRequest(worker, "https://localhost/");
+    expect(await jsonRequest(worker, "https://localhost/")).toEqual({
+      count: 2,
+    });
+    const stats = await worker.stats();
+    expect(stats.inFlight).toEqual(0);
+    expect(stats.requestsServed).toEqual(2);
+    expect(stats.memory.rss).toBeGreaterThan(0);
+
+    // The reloaded module starts with fresh state.
+    await worker.reloadScript();
+    expect(await jsonRequest(worker, "https://localhost/")).toEqual({
+      count: 1,
+    });
+
+    await worker.drain();
+    await expect(jsonRequest(worker, "https://localhost/")).rejects.toThrow();
+    worker.terminate();
+
+    const plain = await newDenoHTTPWorker(echoScript);
+    await expect(plain.stats()).rejects.toBeInstanceOf(InvalidConfigError);
+    plain.terminate();
+  });
+
+  it("reports a response cut short by the process exiting", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          const body = new ReadableStream({
+            async start(controller) {
+              controller.enqueue(new TextEncoder().encode("partial"));
+              await new Promise((resolve) => setTimeout(resolve, 50));
+              Deno.exit(3);
+            },
+          });
+          return new Response(body);
+        }}
+      `
+    );
+    const err = await worker
+      .requestCollect("https://localhost/")
+      .catch((err) => err);
+    expect(err).toBeInstanceOf(IncompleteResponseError);
+    expect(err.receivedBytes).toEqual("partial".length);
+    expect(err.exit).toBeInstanceOf(WorkerExitedDuringRequestError);
+    expect(err.exit.code).toEqual(3);
+  });
+
+  it("reports the worker's uptime", async () => {
+    const worker = await newDenoHTTPWorker(echoScript);
+    const before = worker.uptime;
+    expect(before).toBeGreaterThan(0);
+    await new Promise((resolve) => setTimeout(resolve, 50));
+    expect(worker.uptime).toBeGreaterThan(before + 40);
+    worker.terminate();
+  });
+
+  it("reports the process exiting during a request", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          console.error("going down");
+          await new Promise((resolve) => setTimeout(resolve, 50));
+          Deno.exit(3);
+        }}
+      `
+    );
+    const err = await worker.requestCollect("https://localhost/").then(
+      () => undefined,
+      (err) => err
+    );
+    expect(err).toBeInstanceOf(WorkerExitedDuringRequestError);
+    expect(err.phase).toEqual("request");
+    expect(err.code).toEqual(3);
+    expect(err.stderrTail).toContain("going down");
+  });
+
+  it("registerScript serves several scripts from one process", async () => {
+    const worker = await newDenoHTTPWorker(
+      `
+        export default { async fetch (req: Request): Promise<Response> {
+          return Response.json({ ok: true })
+        }}
+      `,
+      { printOutput: true, controlSocket: true }
+    );
+    const script = (name: string) => `
+      let count = 0;
+      export default { async fetch (req: Request): Promise<Response> {
+        count++;
+        return Response.json({ name: "${name}", count, url: req.url })
+      }}
+    `;
+    await worker.registerScript("a", script("a"));
+    await worker.registerScript("b", script("a"));
+
+    const routed = (key: string) =>
+      new Promise<any>((resolve, reject) => {
+        const req = worker.requestTo(key, "https://localhost/x", {}, (res) => {
+          const body: Buffer[] = [];
+          res.on("data", (chunk) => body.push(chunk));
+          res.on("end", () =>
+            resolve({
+              status: res.statusCode,
+              body: Buffer.concat(body).toString(),
+            })
+          );
         });
+        req.on("error", reject);
+        req.end();
       });
-      req.end();
+
+    const a = await routed("a");
+    expect(JSON.parse(a.body)).toEqual({
+      name: "a",
+      count: 1,
+      url: "https://localhost/x",
     });
-    console.log("http request time", performance.now() - t0);
-    expect(json).toEqual({
-      url: "http://localhost/hi",
-      headers: {},
-      body: "",
-      method: "GET",
+    // The same source under another key is a separate module instance.
+    const b = await routed("b");
+    expect(JSON.parse(b.body).count).toEqual(1);
+    expect((await routed("missing")).status).toEqual(404);
+    expect(await jsonRequest(worker, "https://localhost/")).toEqual({
+      ok: true,
+    });
+
+    const err = await worker.registerScript("c", "export const x = 1;").then(
+      () => undefined,
+      (err) => err
+    );
+    expect(err).toBeInstanceOf(ScriptRegistrationError);
+    expect(err.key).toEqual("c");
+    worker.terminate();
+  });
+
+  it("requests can't register or route to scripts", async () => {
+    const worker = await newDenoHTTPWorker(echoScript, {
+      printOutput: true,
+      controlSocket: true,
     });
+    await worker.registerScript(
+      "other",
+      `export default { fetch: () => new Response("other tenant") };`
+    );
+    for (const headers of [
+      { "X-DENO-WORKER-ROUTE": "other" },
+      { "X-Deno-Worker-Register": "evil" },
+    ]) {
+      const json = await new Promise<any>((resolve, reject) => {
+        const req = worker.request(
+          "https://localhost/",
+          { method: "POST", headers },
+          (res) => {
+            const body: Buffer[] = [];
+            res.on("data", (chunk) => body.push(chunk));
+            res.on("end", () => resolve(JSON.parse(Buffer.concat(body))));
+          }
+        );
+        req.on("error", reject);
+        req.end(`export default { fetch: () => new Response("evil") };`);
+      });
+      expect(json.url).toEqual("https://localhost/");
+      expect(Object.keys(json.headers)).not.toContainEqual(
+        expect.stringMatching(/^x-deno-worker-/)
+      );
+    }
     worker.terminate();
+
+    const plain = await newDenoHTTPWorker(echoScript);
+    const err = await plain.registerScript("a", "").catch((err) => err);
+    expect(err).toBeInstanceOf(InvalidConfigError);
+    plain.terminate();
   });
 
   // it("post with body", async () => {
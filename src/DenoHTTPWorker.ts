@@ -1,12 +1,42 @@
 import path, { resolve } from "node:path";
 import { spawn, SpawnOptions } from "node:child_process";
-import { Readable } from "node:stream";
+import { PassThrough, Readable, Writable, pipeline } from "node:stream";
 import readline from "node:readline";
 import http from "node:http";
+import net from "node:net";
 import fs from "node:fs/promises";
+import {
+  FSWatcher,
+  constants as fsConstants,
+  createWriteStream,
+  watch,
+} from "node:fs";
 import os from "node:os";
 
-import { fileURLToPath } from "url";
+import { fileURLToPath, pathToFileURL } from "url";
+
+import {
+  BootstrapCompileError,
+  ConnectTimeoutError,
+  DenoNotFoundError,
+  DenoWorkerError,
+  IncompleteResponseError,
+  InvalidConfigError,
+  InvalidHandlerError,
+  InvalidUrlError,
+  PermissionDeniedError,
+  ProtocolMismatchError,
+  RequestTimeoutError,
+  ResponseDecodeError,
+  ResponseTooLargeError,
+  ScriptRegistrationError,
+  SocketDirNotWritableError,
+  SocketGoneError,
+  SocketPathTooLongError,
+  StartupTimeoutError,
+  WorkerExitedDuringRequestError,
+  WorkerPhase,
+} from "./errors.js";
 
 const __filename = fileURLToPath(import.meta.url);
 const __dirname = path.dirname(__filename);
@@ -16,16 +46,241 @@ const DEFAULT_DENO_BOOTSTRAP_SCRIPT_PATH = resolve(
   "../deno-bootstrap/index.ts"
 );
 
+// The version of the protocol spoken with the bootstrap script. Must match
+// PROTOCOL_VERSION in deno-bootstrap/index.ts.
+const PROTOCOL_VERSION = 1;
+
+// The longest Unix socket path the OS accepts, in bytes. sun_path is 104
+// bytes on macOS and 108 on Linux, including the terminating NUL.
+const MAX_SOCKET_PATH_LENGTH = os.platform() === "darwin" ? 103 : 107;
+
+// How much of the most recent stdout/stderr output is kept around to attach
+// to errors.
+const OUTPUT_TAIL_LENGTH = 4096;
+
+// How long a terminated worker waits for the process output to end before it
+// stops reading it.
+const OUTPUT_DRAIN_TIMEOUT_MS = 1000;
+
+// How long to wait for the process exit event after a request failed at the
+// connection level before deciding the failure was not caused by a crash.
+const EXIT_ATTRIBUTION_WINDOW_MS = 100;
+
+// How often the memory use of the process is sampled for peakRSS.
+const RSS_SAMPLE_INTERVAL_MS = 1000;
+
+interface ExitStatus {
+  code: number;
+  signal: string;
+}
+
+/**
+ * Additional information about how the worker process exited.
+ */
+export interface ExitDetails {
+  /**
+   * True if the process was killed, either by terminate() or because it did
+   * not exit within gracefulShutdownTimeout after shutdown().
+   */
+  forced: boolean;
+  /**
+   * The peak resident set size of the process in bytes, as last sampled
+   * before it exited. Only available on Linux.
+   */
+  peakRSS?: number;
+}
+
 interface OnExitListener {
-  (exitCode: number, signal: string): void;
+  (exitCode: number, signal: string, details: ExitDetails): void;
+}
+
+/**
+ * A single line of output written by the Deno process.
+ */
+export interface LogLine {
+  stream: "stdout" | "stderr";
+  line: string;
+  /**
+   * The name option of the worker that wrote the line, if set.
+   */
+  name?: string;
+  pid?: number;
+  /**
+   * The parsed line, if parseJSONLogs is enabled and the line is a JSON
+   * object.
+   */
+  json?: unknown;
+}
+
+/**
+ * A request body. Strings and buffers are sent as is. Streams and (async)
+ * iterables are streamed to the worker as they produce data, which means an
+ * incoming http.IncomingMessage or a file opened with fs.createReadStream can
+ * be forwarded without buffering it in memory first.
+ */
+export type RequestBody =
+  | string
+  | Uint8Array
+  | Readable
+  | Iterable<Uint8Array | string>
+  | AsyncIterable<Uint8Array | string>;
+
+/**
+ * W3C trace context headers, see https://www.w3.org/TR/trace-context/.
+ */
+export interface TraceContext {
+  traceparent: string;
+  tracestate?: string;
+}
+
+/**
+ * One of the requests sent by requestBatch.
+ */
+export interface BatchRequest {
+  url: string | URL;
+  options?: http.RequestOptions;
+  body?: RequestBody;
+}
+
+/**
+ * Connection and timing information about a single request. Times are in
+ * milliseconds, measured from when the request was made.
+ */
+export interface ConnectionInfo {
+  /**
+   * True if the request was sent on a pooled connection that had already been
+   * used. False if a new connection was opened for it.
+   */
+  reusedConnection: boolean;
+  /**
+   * How long it took until a new connection was established. 0 when a pooled
+   * connection was reused.
+   */
+  connectTime: number;
+  /**
+   * How long it took until the response headers were received.
+   */
+  timeToFirstByte: number;
+}
+
+/**
+ * A response whose body has been read to completion.
+ */
+export interface CollectedResponse {
+  statusCode: number;
+  statusMessage: string;
+  headers: http.IncomingHttpHeaders;
+  body: Buffer;
+}
+
+/**
+ * An outgoing request as seen by the requestInterceptor option. Both fields
+ * can be changed, or replaced, to alter the request before it is sent.
+ */
+export interface InterceptedRequest {
+  /**
+   * The URL the script will receive, including any basePath.
+   */
+  url: URL;
+  options: http.RequestOptions;
+}
+
+/**
+ * Statistics reported by the Deno process, see DenoHTTPWorker.stats.
+ */
+export interface WorkerStats {
+  /**
+   * Calls to the script's fetch handler that haven't returned a response yet.
+   */
+  inFlight: number;
+  /**
+   * Calls to the script's fetch handler since the process started.
+   */
+  requestsServed: number;
+  /**
+   * The process's memory usage as reported by Deno.memoryUsage(), in bytes.
+   */
+  memory: {
+    rss: number;
+    heapTotal: number;
+    heapUsed: number;
+    external: number;
+  };
+}
+
+/**
+ * How requestWithRetry retries failed requests.
+ */
+export interface RetryPolicy {
+  /**
+   * The maximum number of attempts, including the first one.
+   */
+  maxAttempts: number;
+  /**
+   * Response statuses that are retried. Defaults to all 5xx statuses.
+   */
+  retryStatuses?: number[];
+  /**
+   * Whether a request error is retried. By default errors connecting to or
+   * talking to the Deno process, such as ECONNRESET, are retried.
+   */
+  retryError?: (err: Error) => boolean;
+  /**
+   * How long to wait before the first retry, in milliseconds. Defaults to 50.
+   */
+  backoff?: number;
+  /**
+   * What the wait is multiplied by after each retry. Defaults to 2.
+   */
+  backoffFactor?: number;
+}
+
+/**
+ * A response whose body has been parsed as JSON.
+ */
+export interface JSONResponse {
+  statusCode: number;
+  headers: http.IncomingHttpHeaders;
+  body: any;
+}
+
+/**
+ * A response whose body has been decoded as text.
+ */
+export interface TextResponse {
+  statusCode: number;
+  headers: http.IncomingHttpHeaders;
+  body: string;
+}
+
+/**
+ * Resource limits for the Deno process. Unset limits are inherited from the
+ * parent process.
+ */
+export interface ResourceLimits {
+  /**
+   * The maximum number of open file descriptors.
+   */
+  openFiles?: number;
+  /**
+   * The maximum amount of CPU time, in seconds.
+   */
+  cpuSeconds?: number;
+  /**
+   * The maximum size of the process's virtual memory, in bytes. Rounded down
+   * to whole kilobytes.
+   */
+  addressSpace?: number;
 }
 
-interface MinimalChildProcess {
+export interface MinimalChildProcess {
+  stdin?: Writable | null;
   stdout: Readable | null;
   stderr: Readable | null;
   readonly pid?: number | undefined;
   readonly exitCode: number | null;
   kill(signal?: NodeJS.Signals | number): boolean;
+  unref?(): void;
   on(event: string, listener: (...args: any[]) => void): this;
   on(
     event: "close",
@@ -47,6 +302,12 @@ export interface DenoWorkerOptions {
    */
   denoExecutable: string | string[];
 
+  /**
+   * The Deno subcommand used to run the bootstrap script, placed between the
+   * executable and the run flags. Defaults to ["run"].
+   */
+  denoSubcommand: string[];
+
   /**
    * The path to the script that should be used to bootstrap the worker
    * environment in Deno. If specified, this script will be used instead of the
@@ -67,6 +328,224 @@ export interface DenoWorkerOptions {
    */
   runFlags: string[];
 
+  /**
+   * How a script passed as a string is handed to the Deno process. "argv"
+   * passes it as a command line argument, which makes it visible to other
+   * users through the process list. "stdin" writes it to the process's stdin
+   * instead, which keeps any secrets embedded in the script private. Defaults
+   * to "argv".
+   */
+  scriptTransport: "argv" | "stdin";
+
+  /**
+   * Scripts larger than this many bytes are written to stdin even when
+   * scriptTransport is "argv", because Linux rejects single arguments over
+   * 128KiB with E2BIG. This keeps large bundled scripts working. Defaults to
+   * 64KiB.
+   */
+  maxArgvScriptBytes: number;
+
+  /**
+   * How much state requests share. With "shared" the script is loaded once
+   * and all requests see the same module-level state. With "perRequest" the
+   * script is loaded as a new module instance for every request, so no state
+   * carries over from one request to the next. Modules the script imports are
+   * still shared, and every instance stays in memory, so "perRequest" is best
+   * suited for workers that are replaced regularly. Scripts added with
+   * registerScript are always shared. Defaults to "shared".
+   */
+  isolation: "shared" | "perRequest";
+
+  /**
+   * Module URLs to import while the worker starts, before it accepts
+   * requests. Modules that the script only imports dynamically, on its first
+   * request, are then already downloaded and compiled. Read access is granted
+   * to file: URLs. Defaults to none.
+   */
+  preloadModules: (string | URL)[];
+
+  /**
+   * Additional paths the Deno process is allowed to read. These are merged
+   * into the --allow-read flag along with the paths the worker itself needs,
+   * such as the socket and an imported file: script. A directory grants read
+   * access to everything below it.
+   */
+  allowReadPaths: string[];
+
+  /**
+   * Additional paths the Deno process is allowed to write. These are merged
+   * into the --allow-write flag along with the paths the worker itself needs.
+   */
+  allowWritePaths: string[];
+
+  /**
+   * How long, in milliseconds, to wait for the process to exit after
+   * shutdown() before killing it with SIGKILL. By default the process is given
+   * as long as it needs.
+   */
+  gracefulShutdownTimeout?: number;
+
+  /**
+   * Run the script with `deno run --watch` so that it is restarted whenever
+   * the script file changes. Requests made while the script restarts wait for
+   * it to listen again. Only supported for file: URL scripts and intended for
+   * development.
+   */
+  watch: boolean;
+
+  /**
+   * A path that is prepended to the path of every request URL, so that the
+   * script can be mounted under a prefix without callers knowing about it.
+   * With a basePath of "/app" a request for https://example.com/users is
+   * received by the script as https://example.com/app/users, and one for
+   * https://example.com/ as https://example.com/app/. Leading and trailing
+   * slashes are normalized.
+   */
+  basePath?: string;
+
+  /**
+   * Send the host of the request URL as the Host header when the request
+   * doesn't set one itself. By default the script only receives a Host header
+   * that was set explicitly. Enable this for scripts that route on Host.
+   */
+  preserveHost: boolean;
+
+  /**
+   * The maximum size of a response body that requestCollect will buffer.
+   * Larger responses are aborted and rejected with a ResponseTooLargeError. By
+   * default there is no limit.
+   */
+  maxResponseBytes?: number;
+
+  /**
+   * Maps response status codes to errors. When requestCollect or requestJSON
+   * receives a response with one of these statuses, it rejects with the error
+   * returned by the function instead of resolving, so that a script's own
+   * error conventions, such as a 504 for a timed out upstream call, turn into
+   * errors on this side.
+   */
+  statusErrors?: Record<number, (response: CollectedResponse) => Error>;
+
+  /**
+   * How long to wait for a connection to the Deno process to be established,
+   * in milliseconds. Requests that can't connect in time fail with a
+   * ConnectTimeoutError instead of hanging. By default there is no timeout.
+   */
+  connectTimeout?: number;
+
+  /**
+   * How long a request may take until its response has been read, in
+   * milliseconds. Requests that take longer are aborted with a
+   * RequestTimeoutError. The timeout is sent to the script in the
+   * X-Deno-Worker-Deadline-Ms header, so that a handler can stop its own work
+   * in time, for example with an AbortSignal.timeout of that many
+   * milliseconds. By default there is no timeout.
+   */
+  requestTimeout?: number;
+
+  /**
+   * After this many consecutive requests fail at the connection level, for
+   * example with ECONNRESET, the pooled connections are thrown away and new
+   * ones are opened, so that a broken connection pool doesn't keep failing
+   * requests. Requests in flight on the old connections are not affected.
+   * Defaults to 3.
+   */
+  connectionFailureThreshold: number;
+
+  /**
+   * How long to wait for the Deno process to become ready, in milliseconds.
+   * If it isn't ready in time the process is killed and newDenoHTTPWorker
+   * rejects with a StartupTimeoutError that includes the output printed so
+   * far. By default there is no timeout.
+   */
+  startupTimeout?: number;
+
+  /**
+   * By default the warm requests are answered by the bootstrap and never reach
+   * the script. Set this to an absolute URL to have them sent to the script
+   * instead, for example to let it initialize itself before real traffic
+   * arrives. They carry an "X-Deno-Worker-Warmup: 1" header so that the
+   * script can tell them apart from real requests.
+   */
+  warmRequestURL?: string;
+
+  /**
+   * How many times a failed warm request is retried before newDenoHTTPWorker
+   * fails. Connections that are refused because the script doesn't listen on
+   * its socket yet are always retried and don't count towards this; use
+   * startupTimeout to bound how long that may take. Defaults to 0.
+   */
+  warmRequestRetries: number;
+
+  /**
+   * How long to wait between warm request retries, in milliseconds. Defaults
+   * to 20.
+   */
+  warmRequestRetryInterval: number;
+
+  /**
+   * How to wait for the Deno process to create its socket. "poll" checks for
+   * the socket file every 20ms. "watch" watches the socket's directory for
+   * changes (inotify on Linux), which notices the socket as soon as it is
+   * created, and falls back to polling where watching isn't supported.
+   * Defaults to "poll".
+   */
+  socketWaitStrategy: "poll" | "watch";
+
+  /**
+   * The number of connections to open to the Deno process before the worker
+   * is returned. Opening several up front means that an early burst of
+   * concurrent requests does not have to wait for new connections. Defaults to
+   * 1.
+   */
+  warmConnections: number;
+
+  /**
+   * Arguments for the script. The process arguments start with the ones the
+   * bootstrap needs, so they are passed after a "--" argument, where the
+   * script finds them with Deno.args.slice(Deno.args.indexOf("--") + 1).
+   * Defaults to no arguments.
+   */
+  scriptArgs: string[];
+
+  /**
+   * The directory the worker's socket files are created in. Defaults to the
+   * operating system's temp directory.
+   */
+  socketDir?: string;
+
+  /**
+   * Put this in front of the socket file names, for example a tenant ID, so
+   * that sockets in `ss -x` output or in the socket directory can be told
+   * apart. A random ID is still part of the name to keep it unique. Must not
+   * contain a path separator.
+   */
+  socketPrefix?: string;
+
+  /**
+   * Generates the unique part of the socket file names. Defaults to
+   * crypto.randomUUID. Tests can pass a deterministic generator to assert on
+   * socket paths, but the IDs of workers sharing a socketDir must not collide.
+   * Must not return a path separator. Called again each time the worker
+   * process is replaced.
+   */
+  generateSocketId?: () => string;
+
+  /**
+   * Leave the socket files in place after the worker terminates, for example
+   * to inspect them after a crash. Otherwise the worker removes them once it
+   * terminates; the bootstrap script never removes them on exit. Defaults to
+   * false.
+   */
+  keepSocketOnExit: boolean;
+
+  /**
+   * Open a second socket for admin commands, which enables reloadScript,
+   * registerScript, drain and stats. Commands on it don't queue behind
+   * requests on the main socket. Defaults to false.
+   */
+  controlSocket: boolean;
+
   /**
    * Print stdout and stderr to the console with a "[deno]" prefix. This is
    * useful for debugging.
@@ -74,7 +553,102 @@ export interface DenoWorkerOptions {
   printOutput: boolean;
 
   /**
-   * Print out the command and arguments that are executed.
+   * A name for the worker. It is included in printed output ("[deno:name]")
+   * and in the lines passed to onLog, so that output from many workers can be
+   * told apart.
+   */
+  name?: string;
+
+  /**
+   * Called with every line the Deno process writes to stdout or stderr,
+   * tagged with the worker name and pid. Use this to forward worker output
+   * into a structured logger.
+   */
+  onLog?: (line: LogLine) => void;
+
+  /**
+   * Called with every outgoing request right before it is sent to the Deno
+   * process. Use this to add auth or tracing headers or to rewrite URLs in one
+   * place instead of at each call site.
+   */
+  requestInterceptor?: (request: InterceptedRequest) => void;
+
+  /**
+   * Headers added to every request, for example an auth token or an API
+   * version. A header of the same name passed to a request call, in any
+   * casing, replaces the default. The requestInterceptor sees the merged
+   * headers, except for requestWithHeaderList: there it is called with empty
+   * headers, and the headers it sets are sent after the given list and the
+   * defaults.
+   */
+  defaultHeaders?: Record<string, string>;
+
+  /**
+   * Called for every request to get the W3C trace context of the caller's
+   * current span, which is sent along as the traceparent and tracestate
+   * headers so that the script can continue the trace. With OpenTelemetry,
+   * return the carrier filled by `propagation.inject(context.active(), {})`.
+   * Headers passed to a request call take precedence.
+   */
+  traceContext?: () => TraceContext | undefined;
+
+  /**
+   * Called with every response from the Deno process, along with the request
+   * it answers, before it is passed to the request callback. Use this to
+   * record metrics or to change or remove response headers in one place.
+   */
+  responseInterceptor?: (
+    response: http.IncomingMessage,
+    request: InterceptedRequest
+  ) => void;
+
+  /**
+   * Response headers to pass on, matched case-insensitively. When set, every
+   * other header is removed from responses before they reach the request
+   * callback or a CollectedResponse, so that headers meant for internal use
+   * don't leak to downstream clients when proxying. The responseInterceptor
+   * still sees all headers.
+   */
+  responseHeaderAllowlist?: string[];
+
+  /**
+   * Parse stderr lines that are JSON objects, such as Deno's structured log
+   * output, and include the parsed value as the json field of the LogLine.
+   */
+  parseJSONLogs: boolean;
+
+  /**
+   * How many output lines capturedLogs keeps, which bounds the memory used by
+   * a worker that logs heavily. 0 turns capturing off. Defaults to 1000.
+   */
+  capturedLogLines: number;
+
+  /**
+   * Which lines capturedLogs gives up once capturedLogLines are kept.
+   * "dropOldest" keeps the most recent output, "dropNewest" keeps the output
+   * from startup. In both cases the lines still go to onLog and the output
+   * streams. There is no mode that waits for room instead: reading
+   * capturedLogs never frees any, so waiting would stop reading the process's
+   * output for good and stall Deno on its next write to stdout or stderr.
+   * Defaults to "dropOldest".
+   */
+  capturedLogOverflow: "dropOldest" | "dropNewest";
+
+  /**
+   * Append every line the Deno process writes to stdout to this file. The file
+   * is created if it doesn't exist. This works independently of printOutput.
+   */
+  stdoutFile?: string;
+
+  /**
+   * Append every line the Deno process writes to stderr to this file. It may
+   * be the same file as stdoutFile.
+   */
+  stderrFile?: string;
+
+  /**
+   * Print out the command and arguments that are executed. Use
+   * resolveWorkerCommand to get them without starting a worker.
    */
   printCommandAndArguments: boolean;
 
@@ -83,6 +657,36 @@ export interface DenoWorkerOptions {
    */
   spawnOptions: SpawnOptions;
 
+  /**
+   * The directory Deno caches downloaded modules in, passed to the process as
+   * the DENO_DIR environment variable. Workers can share a cache or be kept
+   * apart by giving them the same or different directories. By default Deno's
+   * own default is used.
+   */
+  denoDir?: string;
+
+  /**
+   * A proxy URL that the script's outgoing fetch calls are sent through, so
+   * that its egress can be observed and restricted. It is passed to the
+   * process as the HTTP_PROXY and HTTPS_PROXY environment variables, and the
+   * proxy's host is added to a --allow-net=... flag if there is one.
+   */
+  egressProxy?: string;
+
+  /**
+   * Limits applied to the Deno process with the shell's ulimit before it
+   * starts, so that a misbehaving script can't exhaust the host's resources.
+   * Each limit sets both the soft and the hard limit. Only supported on Unix.
+   */
+  resourceLimits?: ResourceLimits;
+
+  /**
+   * The CPU cores the Deno process is allowed to run on, for example [2, 3].
+   * The affinity is set with taskset before Deno starts, so this is only
+   * supported on Linux hosts that have taskset installed.
+   */
+  cpuAffinity?: number[];
+
   /**
    * Callback that is called when the process is spawned.
    */
@@ -98,20 +702,39 @@ export interface DenoWorkerOptions {
   ) => MinimalChildProcess;
 }
 
-/**
- * Create a new DenoHTTPWorker. This function will start a worker and being
- */
-export const newDenoHTTPWorker = async (
+// Resolves the options and everything derived from them that is needed to
+// spawn the Deno process, without spawning it.
+function resolveSpawn(
   script: string | URL,
-  options: Partial<DenoWorkerOptions> = {}
-): Promise<DenoHTTPWorker> => {
+  options: Partial<DenoWorkerOptions>
+) {
   const _options: DenoWorkerOptions = {
     denoExecutable: "deno",
+    denoSubcommand: ["run"],
     denoBootstrapScriptPath: DEFAULT_DENO_BOOTSTRAP_SCRIPT_PATH,
     runFlags: [],
+    scriptTransport: "argv",
+    maxArgvScriptBytes: 64 * 1024,
+    isolation: "shared",
+    preloadModules: [],
+    allowReadPaths: [],
+    allowWritePaths: [],
+    watch: false,
+    preserveHost: false,
+    scriptArgs: [],
+    controlSocket: false,
+    keepSocketOnExit: false,
+    warmConnections: 1,
+    connectionFailureThreshold: 3,
+    warmRequestRetries: 0,
+    warmRequestRetryInterval: 20,
+    socketWaitStrategy: "poll",
     printCommandAndArguments: false,
     spawnOptions: {},
     printOutput: false,
+    parseJSONLogs: false,
+    capturedLogLines: 1000,
+    capturedLogOverflow: "dropOldest",
     spawnFunc: spawn,
     ...options,
   };
@@ -119,125 +742,472 @@ export const newDenoHTTPWorker = async (
   let scriptArgs: string[];
 
   // Create the socket location that we'll use to communicate with Deno.
-  const socketFile = path.join(
-    os.tmpdir(),
-    `${crypto.randomUUID()}-deno-http.sock`
-  );
+  const socketDir = _options.socketDir ?? os.tmpdir();
+  if (_options.socketPrefix?.includes("/")) {
+    throw new InvalidConfigError("socketPrefix must not contain a /");
+  }
+  const uniqueId = (_options.generateSocketId ?? crypto.randomUUID)();
+  if (uniqueId === "" || uniqueId.includes("/")) {
+    throw new InvalidConfigError(
+      "generateSocketId must return a non-empty ID without a /"
+    );
+  }
+  const socketId = _options.socketPrefix
+    ? `${_options.socketPrefix}-${uniqueId}`
+    : uniqueId;
+  const socketFile = path.join(socketDir, `${socketId}-deno-http.sock`);
+  const controlSocketFile = _options.controlSocket
+    ? path.join(socketDir, `${socketId}-control-deno-http.sock`)
+    : undefined;
+  const socketFiles = controlSocketFile
+    ? [socketFile, controlSocketFile]
+    : [socketFile];
+  for (const file of socketFiles) {
+    if (Buffer.byteLength(file) > MAX_SOCKET_PATH_LENGTH) {
+      throw new SocketPathTooLongError(file, MAX_SOCKET_PATH_LENGTH);
+    }
+  }
 
   // If we have a file import, make sure we allow read access to the file.
-  const allowReadFlagValue =
-    typeof script === "string"
-      ? socketFile
-      : `${socketFile},${script.href.replace("file://", "")}`;
+  const allowReadPaths = [...socketFiles, ..._options.allowReadPaths];
+  if (typeof script !== "string" && script.protocol === "file:") {
+    allowReadPaths.push(fileURLToPath(script));
+  }
+  const preloadURLs = _options.preloadModules.map((url) => parseURL(url));
+  for (const url of preloadURLs) {
+    if (url.protocol === "file:") allowReadPaths.push(fileURLToPath(url));
+  }
+  _options.runFlags = prepareRunFlags(_options.runFlags, allowReadPaths, [
+    ...socketFiles,
+    ..._options.allowWritePaths,
+  ]);
+  if (_options.egressProxy !== undefined) {
+    const proxyHost = parseURL(_options.egressProxy).host;
+    _options.runFlags = _options.runFlags.map((flag) =>
+      flag.startsWith("--allow-net=") ? `${flag},${proxyHost}` : flag
+    );
+  }
+  // Permissions that weren't granted fail instead of waiting for a prompt
+  // answer that never comes.
+  if (!_options.runFlags.includes("--no-prompt")) {
+    _options.runFlags.push("--no-prompt");
+  }
 
-  let allowReadFound = false;
-  let allowWriteFound = false;
-  _options.runFlags = _options.runFlags.map((flag) => {
-    if (flag === "--allow-read" || flag === "--allow-all") {
-      allowReadFound = true;
-    }
-    if (flag === "--allow-write" || flag === "--allow-all") {
-      allowWriteFound = true;
-    }
-    if (flag.startsWith("--allow-read=")) {
-      allowReadFound = true;
-      return (flag += "," + allowReadFlagValue);
+  if (_options.watch) {
+    if (typeof script === "string" || script.protocol !== "file:") {
+      throw new InvalidConfigError("watch is only supported for file: URLs");
     }
-    if (flag.startsWith("--allow-write=")) {
-      allowWriteFound = true;
-      return (flag += "," + socketFile);
-    }
-    return flag;
-  });
-  if (!allowReadFound) {
-    _options.runFlags.push("--allow-read=" + allowReadFlagValue);
-  }
-  if (!allowWriteFound) {
-    _options.runFlags.push("--allow-write=" + socketFile);
+    _options.runFlags.push(
+      `--watch=${fileURLToPath(script)}`,
+      "--no-clear-screen"
+    );
   }
 
+  const scriptTransport =
+    typeof script === "string" &&
+    Buffer.byteLength(script) > _options.maxArgvScriptBytes
+      ? "stdin"
+      : _options.scriptTransport;
   if (typeof script === "string") {
-    scriptArgs = [socketFile, "script", script];
+    scriptArgs =
+      scriptTransport === "stdin"
+        ? [socketFile, "stdin"]
+        : [socketFile, "script", script];
   } else {
     scriptArgs = [socketFile, "import", script.href];
   }
+  if (controlSocketFile) {
+    scriptArgs.push(`--control-socket=${controlSocketFile}`);
+  }
+  if (_options.isolation === "perRequest") {
+    scriptArgs.push("--isolation=per-request");
+  }
+  for (const url of preloadURLs) {
+    scriptArgs.push(`--preload=${url.href}`);
+  }
+  // Always pass the separator, so that the script's arguments are the ones
+  // after it even if there are none.
+  scriptArgs.push("--", ..._options.scriptArgs);
   if (
     Array.isArray(_options.denoExecutable) &&
     _options.denoExecutable.length === 0
   ) {
-    throw new Error("denoExecutable must not be an empty array");
+    throw new InvalidConfigError("denoExecutable must not be an empty array");
   }
   const command =
     typeof _options.denoExecutable === "string"
       ? _options.denoExecutable
       : (_options.denoExecutable[0] as string);
+  if (command === "") {
+    throw new InvalidConfigError("denoExecutable must not be empty");
+  }
+  if (
+    !Number.isInteger(_options.capturedLogLines) ||
+    _options.capturedLogLines < 0
+  ) {
+    throw new InvalidConfigError(
+      "capturedLogLines must be a non-negative integer"
+    );
+  }
+  if (
+    !Number.isInteger(_options.warmConnections) ||
+    _options.warmConnections < 1
+  ) {
+    throw new InvalidConfigError("warmConnections must be a positive integer");
+  }
+  if (
+    !Number.isInteger(_options.connectionFailureThreshold) ||
+    _options.connectionFailureThreshold < 1
+  ) {
+    throw new InvalidConfigError(
+      "connectionFailureThreshold must be a positive integer"
+    );
+  }
+  if (_options.connectTimeout !== undefined && !(_options.connectTimeout > 0)) {
+    throw new InvalidConfigError("connectTimeout must be a positive number");
+  }
+  if (_options.requestTimeout !== undefined && !(_options.requestTimeout > 0)) {
+    throw new InvalidConfigError("requestTimeout must be a positive number");
+  }
+  if (_options.startupTimeout !== undefined && !(_options.startupTimeout > 0)) {
+    throw new InvalidConfigError("startupTimeout must be a positive number");
+  }
+  if (_options.warmRequestURL !== undefined) {
+    parseURL(_options.warmRequestURL);
+  }
+  if (_options.cpuAffinity !== undefined) {
+    if (os.platform() !== "linux") {
+      throw new InvalidConfigError("cpuAffinity is only supported on Linux");
+    }
+    if (
+      _options.cpuAffinity.length === 0 ||
+      !_options.cpuAffinity.every((cpu) => Number.isInteger(cpu) && cpu >= 0)
+    ) {
+      throw new InvalidConfigError(
+        "cpuAffinity must be a non-empty list of CPU numbers"
+      );
+    }
+  }
+  for (const [name, limit] of Object.entries(_options.resourceLimits ?? {})) {
+    if (limit !== undefined && !(Number.isInteger(limit) && limit > 0)) {
+      throw new InvalidConfigError(
+        `resourceLimits.${name} must be a positive integer`
+      );
+    }
+  }
+
+  const args = [
+    ...(typeof _options.denoExecutable === "string"
+      ? []
+      : _options.denoExecutable.slice(1)),
+    ..._options.denoSubcommand,
+    ..._options.runFlags,
+    _options.denoBootstrapScriptPath,
+    ...scriptArgs,
+  ];
+  let [spawnCommand, spawnArgs] = _options.resourceLimits
+    ? withResourceLimits(_options.resourceLimits, command, args)
+    : [command, args];
+  if (_options.cpuAffinity) {
+    // taskset execs the command, so the pid stays the Deno process's.
+    [spawnCommand, spawnArgs] = [
+      "taskset",
+      ["-c", _options.cpuAffinity.join(","), spawnCommand, ...spawnArgs],
+    ];
+  }
+
+  const env: NodeJS.ProcessEnv = {};
+  if (_options.denoDir !== undefined) {
+    env.DENO_DIR = _options.denoDir;
+  }
+  if (_options.egressProxy !== undefined) {
+    env.HTTP_PROXY = env.HTTPS_PROXY = _options.egressProxy;
+  }
+  return {
+    _options,
+    command,
+    spawnCommand,
+    spawnArgs,
+    env,
+    socketFile,
+    controlSocketFile,
+    socketFiles,
+    scriptTransport,
+  };
+}
+
+/**
+ * The command a worker would be started with, as returned by
+ * resolveWorkerCommand.
+ */
+export interface ResolvedCommand {
+  command: string;
+  args: string[];
+  /**
+   * Environment variables that are set in addition to the spawnOptions
+   * environment, or the environment of this process.
+   */
+  env: NodeJS.ProcessEnv;
+}
+
+/**
+ * Resolves the command and arguments that newDenoHTTPWorker would spawn the
+ * Deno process with for these options, including the merged permission flags,
+ * without spawning it. The socket paths in the arguments contain a new random
+ * ID each time unless generateSocketId is set. Throws the same configuration
+ * errors as newDenoHTTPWorker.
+ */
+export function resolveWorkerCommand(
+  script: string | URL,
+  options: Partial<DenoWorkerOptions> = {}
+): ResolvedCommand {
+  const { spawnCommand, spawnArgs, env } = resolveSpawn(script, options);
+  return { command: spawnCommand, args: spawnArgs, env };
+}
+
+/**
+ * Create a new DenoHTTPWorker. This function will start a worker and being
+ */
+export const newDenoHTTPWorker = async (
+  script: string | URL,
+  options: Partial<DenoWorkerOptions> = {}
+): Promise<DenoHTTPWorker> => {
+  const {
+    _options,
+    command,
+    spawnCommand,
+    spawnArgs,
+    env,
+    socketFile,
+    controlSocketFile,
+    socketFiles,
+    scriptTransport,
+  } = resolveSpawn(script, options);
+
+  // Otherwise the process fails to create its socket and startup only ends
+  // once it exits or times out.
+  const socketDir = path.dirname(socketFile);
+  try {
+    await fs.access(socketDir, fsConstants.W_OK | fsConstants.X_OK);
+  } catch (err) {
+    throw new SocketDirNotWritableError(
+      socketDir,
+      (err as NodeJS.ErrnoException).code ?? "unknown"
+    );
+  }
 
   return new Promise((resolve, reject) => {
     (async (): Promise<DenoHTTPWorker> => {
-      const args = [
-        ...(typeof _options.denoExecutable === "string"
-          ? []
-          : _options.denoExecutable.slice(1)),
-        "run",
-        ..._options.runFlags,
-        _options.denoBootstrapScriptPath,
-        ...scriptArgs,
-      ];
       if (_options.printCommandAndArguments) {
-        console.log("Spawning deno process:", [command, ...args]);
+        console.log("Spawning deno process:", [spawnCommand, ...spawnArgs]);
       }
 
-      const process = _options.spawnFunc(command, args, _options.spawnOptions);
+      const spawnOptions =
+        Object.keys(env).length === 0
+          ? _options.spawnOptions
+          : {
+              ..._options.spawnOptions,
+              env: {
+                ...(_options.spawnOptions.env ?? globalThis.process.env),
+                ...env,
+              },
+            };
+      const process = _options.spawnFunc(spawnCommand, spawnArgs, spawnOptions);
+      const startedAt = performance.now();
       let running = false;
+      // Set once the warm requests succeeded and the worker is handed out.
+      let ready = false;
       let exited = false;
       let worker: DenoHTTPWorker | undefined = undefined;
+
+      // The process output is piped through so that we can keep a tail of it
+      // for error reporting without consuming the streams we hand to callers.
+      // Everything on this side reads the pass-through streams: a pipe pauses
+      // its source while the destination is full, so an unread one would stop
+      // the output and eventually block the process on writing it.
+      const stdout = new PassThrough();
+      const stderr = new PassThrough();
+      process.stdout?.pipe(stdout);
+      process.stderr?.pipe(stderr);
+      const stdoutTail = new outputTail(process.stdout && stdout);
+      const stderrTail = new outputTail(process.stderr && stderr);
+
+      let timedOut: StartupTimeoutError | undefined;
+      const startupTimeout = _options.startupTimeout;
+      const startupTimer =
+        startupTimeout === undefined
+          ? undefined
+          : setTimeout(() => {
+              timedOut = withPhase(
+                new StartupTimeoutError(
+                  startupTimeout,
+                  stdoutTail.toString(),
+                  stderrTail.toString(),
+                  !exited
+                ),
+                "startup"
+              );
+              reject(timedOut);
+              if (worker) {
+                worker.terminate();
+              } else {
+                process.kill("SIGKILL");
+              }
+            }, startupTimeout);
+
+      const processExit = new Promise<void>((resolve) => {
+        process.on("exit", () => resolve());
+        process.on("error", () => resolve());
+      });
       process.on("exit", (code: number, signal: string) => {
         exited = true;
-        if (!running) {
-          const stderr = process.stderr?.read()?.toString();
-          const stdout = process.stdout?.read()?.toString();
+        if (!ready) {
+          clearTimeout(startupTimer);
+          const diagnostics = bootstrapCompileErrors(
+            stderrTail.toString(),
+            _options.denoBootstrapScriptPath
+          );
           reject(
-            Object.assign(new Error("Deno exited before being ready"), {
-              stderr,
-              stdout,
-              code,
-              signal,
-            })
+            diagnostics !== undefined
+              ? withPhase(new BootstrapCompileError(diagnostics), "startup")
+              : wrapperExecFailed(_options, code, stderrTail.toString())
+              ? withPhase(new DenoNotFoundError(command), "spawn")
+              : Object.assign(new Error("Deno exited before being ready"), {
+                  stderr: stderrTail.toString(),
+                  stdout: stdoutTail.toString(),
+                  code,
+                  signal,
+                  phase: "startup",
+                })
           );
-          fs.rm(socketFile).catch(() => {});
+        }
+        if (!running) {
+          // There is no worker yet that would clean up after the process.
+          if (!_options.keepSocketOnExit) removeSocketFiles(socketFiles);
         } else {
-          (worker as denoHTTPWorker)._terminate(code, signal);
+          processOwners.get(process)?._terminate(code, signal);
+        }
+      });
+      // "error" is emitted instead of "exit" when the process could not be
+      // spawned at all.
+      process.on("error", (err: NodeJS.ErrnoException) => {
+        if (ready) {
+          return;
         }
+        exited = true;
+        clearTimeout(startupTimer);
+        reject(
+          withPhase(
+            err.code !== "ENOENT"
+              ? err
+              : _options.cpuAffinity
+              ? new InvalidConfigError("cpuAffinity requires taskset")
+              : new DenoNotFoundError(command),
+            "spawn"
+          )
+        );
       });
       options.onSpawn && options.onSpawn(process);
-      const stdout = <Readable>process.stdout;
-      const stderr = <Readable>process.stderr;
 
-      if (_options.printOutput) {
-        readline.createInterface({ input: stdout }).on("line", (line) => {
-          console.log("[deno]", line);
-        });
-        readline.createInterface({ input: stderr }).on("line", (line) => {
-          console.error("[deno]", line);
-        });
+      if (typeof script === "string" && scriptTransport === "stdin") {
+        if (!process.stdin) {
+          process.kill();
+          throw new InvalidConfigError(
+            'scriptTransport "stdin" requires the process to have a stdin pipe'
+          );
+        }
+        // Errors are reported through the exit handler if the process dies
+        // before reading the script.
+        process.stdin.on("error", () => {});
+        process.stdin.end(script);
       }
 
-      // Wait for the socket file to be created by the Deno process.
-      for (;;) {
-        if (exited) {
-          break;
+      const prefix = _options.name ? `[deno:${_options.name}]` : "[deno]";
+      const capturedLogs: LogLine[] = [];
+      const onLine = (stream: LogLine["stream"], line: string) => {
+        if (_options.printOutput) {
+          (stream === "stdout" ? console.log : console.error)(prefix, line);
         }
-        try {
-          await fs.stat(socketFile);
-          // File exists
-          break;
-        } catch (err) {
-          await new Promise((resolve) => setTimeout(resolve, 20));
+        const logLine: LogLine = {
+          stream,
+          line,
+          name: _options.name,
+          pid: process.pid,
+        };
+        if (_options.parseJSONLogs && stream === "stderr") {
+          const json = parseJSONLine(line);
+          if (json !== undefined) logLine.json = json;
+        }
+        if (capturedLogs.length < _options.capturedLogLines) {
+          capturedLogs.push(logLine);
+        } else if (
+          _options.capturedLogOverflow === "dropOldest" &&
+          _options.capturedLogLines > 0
+        ) {
+          capturedLogs.shift();
+          capturedLogs.push(logLine);
+        }
+        _options.onLog?.(logLine);
+        if (_options.watch && running && line.includes("Restarting!")) {
+          processOwners.get(process)?._reload();
+        }
+      };
+      const outputReaders: OutputReader[] = [];
+      for (const stream of ["stdout", "stderr"] as const) {
+        if (process[stream]) {
+          const input = stream === "stdout" ? stdout : stderr;
+          const file =
+            stream === "stdout" ? _options.stdoutFile : _options.stderrFile;
+          const logFile =
+            file === undefined
+              ? undefined
+              : createWriteStream(file, { flags: "a" });
+          logFile?.on("error", (err) => {
+            console.error(prefix, `Could not write ${stream} to ${file}:`, err);
+          });
+          const lines = readline.createInterface({ input });
+          lines.on("line", (line) => {
+            onLine(stream, line);
+            logFile?.write(line + "\n");
+          });
+          const closed = new Promise<void>((resolve) => {
+            lines.on("close", () =>
+              logFile ? logFile.end(() => resolve()) : resolve()
+            );
+          });
+          outputReaders.push({ lines, closed });
         }
       }
-      worker = new denoHTTPWorker(socketFile, process, stdout, stderr);
+
+      worker = new denoHTTPWorker(
+        socketFile,
+        controlSocketFile,
+        process,
+        stdout,
+        stderr,
+        stderrTail,
+        capturedLogs,
+        processExit,
+        outputReaders,
+        startedAt,
+        _options,
+        options
+      );
+      // From here on the worker owns the process, so an exit at any point
+      // during startup terminates it and stops waitUntilReady.
       running = true;
-      await (worker as denoHTTPWorker).warmRequest();
+      try {
+        await (worker as denoHTTPWorker).waitUntilReady(
+          () => exited || timedOut !== undefined
+        );
+      } catch (err) {
+        // Don't leave a process behind that nobody can reach.
+        if (!exited) worker.terminate();
+        throw withPhase(timedOut ?? err, "startup");
+      } finally {
+        clearTimeout(startupTimer);
+      }
+      ready = true;
 
       return worker;
     })()
@@ -246,22 +1216,300 @@ export const newDenoHTTPWorker = async (
   });
 };
 
+// When the command is wrapped in /bin/sh or taskset, a missing executable
+// doesn't fail the spawn with ENOENT. The wrapper reports it on stderr and
+// exits with 127 instead.
+function wrapperExecFailed(
+  options: DenoWorkerOptions,
+  code: number | null,
+  stderr: string
+): boolean {
+  return (
+    (options.resourceLimits !== undefined || !!options.cpuAffinity) &&
+    code === 127 &&
+    /not found|No such file or directory/.test(stderr)
+  );
+}
+
+// Wraps the command in a shell that applies the limits with ulimit and then
+// execs the command, so that the limits apply to the Deno process itself.
+function withResourceLimits(
+  limits: ResourceLimits,
+  command: string,
+  args: string[]
+): [string, string[]] {
+  const ulimits: string[] = [];
+  if (limits.openFiles !== undefined) {
+    ulimits.push(`ulimit -n ${limits.openFiles}`);
+  }
+  if (limits.cpuSeconds !== undefined) {
+    ulimits.push(`ulimit -t ${limits.cpuSeconds}`);
+  }
+  if (limits.addressSpace !== undefined) {
+    ulimits.push(`ulimit -v ${Math.floor(limits.addressSpace / 1024)}`);
+  }
+  const script = [...ulimits, 'exec "$@"'].join(" && ");
+  return ["/bin/sh", ["-c", script, "sh", command, ...args]];
+}
+
+/**
+ * Makes sure the run flags grant read and write access to the given paths.
+ * Paths are appended to existing --allow-read=/--allow-write= flags, or added
+ * as new flags if there are none. Flags that already grant full access are
+ * left alone. The resulting path lists are normalized and deduplicated.
+ */
+function prepareRunFlags(
+  runFlags: string[],
+  readPaths: string[],
+  writePaths: string[]
+): string[] {
+  let allowReadFound = false;
+  let allowWriteFound = false;
+  const flags = runFlags.map((flag) => {
+    if (flag === "--allow-read" || flag === "--allow-all") {
+      allowReadFound = true;
+    }
+    if (flag === "--allow-write" || flag === "--allow-all") {
+      allowWriteFound = true;
+    }
+    if (flag.startsWith("--allow-read=")) {
+      allowReadFound = true;
+      return "--allow-read=" + mergePaths(flag, readPaths);
+    }
+    if (flag.startsWith("--allow-write=")) {
+      allowWriteFound = true;
+      return "--allow-write=" + mergePaths(flag, writePaths);
+    }
+    return flag;
+  });
+  if (!allowReadFound) {
+    flags.push("--allow-read=" + mergePaths("", readPaths));
+  }
+  if (!allowWriteFound) {
+    flags.push("--allow-write=" + mergePaths("", writePaths));
+  }
+  return flags;
+}
+
+/**
+ * Joins the paths already listed in a --allow-read=/--allow-write= flag with
+ * the added ones. "." and ".." segments and trailing slashes are resolved
+ * lexically, so the result doesn't depend on what exists on disk, and
+ * repeated paths are listed once.
+ */
+function mergePaths(flag: string, added: string[]): string {
+  const existing = flag.slice(flag.indexOf("=") + 1).split(",");
+  const paths = new Set<string>();
+  for (const p of [...existing, ...added]) {
+    if (p !== "") {
+      const normalized = path.normalize(p);
+      paths.add(
+        normalized.length > 1 && normalized.endsWith(path.sep)
+          ? normalized.slice(0, -1)
+          : normalized
+      );
+    }
+  }
+  return [...paths].join(",");
+}
+
+/**
+ * Create a new DenoHTTPWorker that runs the script at the given file path.
+ * Relative paths are resolved against the current working directory. The
+ * script's directory is added to allowReadPaths so that the script can import
+ * its sibling modules.
+ */
+export const newDenoHTTPWorkerFromPath = async (
+  scriptPath: string,
+  options: Partial<DenoWorkerOptions> = {}
+): Promise<DenoHTTPWorker> => {
+  const absolutePath = resolve(scriptPath);
+  return newDenoHTTPWorker(pathToFileURL(absolutePath), {
+    ...options,
+    allowReadPaths: [
+      ...(options.allowReadPaths ?? []),
+      path.dirname(absolutePath),
+    ],
+  });
+};
+
+/**
+ * Create a new DenoHTTPWorker that runs a script given as raw bytes, for
+ * example bundler output with a byte order mark. The bytes are written to a
+ * temporary file as is and Deno decodes them when it loads the file, instead
+ * of them being decoded as UTF-8 on this side. The file is removed once the
+ * worker exits.
+ */
+export const newDenoHTTPWorkerFromBytes = async (
+  script: Uint8Array,
+  options: Partial<DenoWorkerOptions> = {}
+): Promise<DenoHTTPWorker> => {
+  const dir = await fs.mkdtemp(path.join(os.tmpdir(), "deno-http-worker-"));
+  const remove = () => fs.rm(dir, { recursive: true, force: true });
+  const scriptPath = path.join(dir, "script.tsx");
+  try {
+    await fs.writeFile(scriptPath, script);
+    const worker = await newDenoHTTPWorker(pathToFileURL(scriptPath), options);
+    worker.addEventListener("exit", () => {
+      remove().catch(() => {});
+    });
+    return worker;
+  } catch (err) {
+    await remove().catch(() => {});
+    throw err;
+  }
+};
+
+/**
+ * Returns a copy of headers with clientAddress appended to the
+ * X-Forwarded-For and Forwarded headers, keeping any proxies already listed
+ * in them, so that a script behind the worker can tell which client a
+ * proxied request came from. Pass the result as the headers of a request.
+ */
+export function withForwardedFor(
+  headers: http.OutgoingHttpHeaders,
+  clientAddress: string
+): http.OutgoingHttpHeaders {
+  const forwarded = net.isIPv6(clientAddress)
+    ? `for="[${clientAddress}]"`
+    : `for=${clientAddress}`;
+  const result: http.OutgoingHttpHeaders = {};
+  let forwardedFor: string | undefined;
+  let forwardedChain: string | undefined;
+  for (const [name, value] of Object.entries(headers)) {
+    const joined = Array.isArray(value) ? value.join(", ") : value;
+    switch (name.toLowerCase()) {
+      case "x-forwarded-for":
+        forwardedFor = String(joined);
+        break;
+      case "forwarded":
+        forwardedChain = String(joined);
+        break;
+      default:
+        result[name] = value;
+    }
+  }
+  result["x-forwarded-for"] = forwardedFor
+    ? `${forwardedFor}, ${clientAddress}`
+    : clientAddress;
+  result["forwarded"] = forwardedChain
+    ? `${forwardedChain}, ${forwarded}`
+    : forwarded;
+  return result;
+}
+
+/**
+ * Create count workers running the same script. All processes are started at
+ * once so that their startups overlap instead of running one after another.
+ * If any of them fails to start, the ones that did start are terminated and
+ * the first error is thrown.
+ */
+export const newDenoHTTPWorkers = async (
+  script: string | URL,
+  count: number,
+  options: Partial<DenoWorkerOptions> = {}
+): Promise<DenoHTTPWorker[]> => {
+  if (!Number.isInteger(count) || count < 0) {
+    throw new InvalidConfigError("count must be a non-negative integer");
+  }
+  const results = await Promise.allSettled(
+    Array.from({ length: count }, () => newDenoHTTPWorker(script, options))
+  );
+  const failed = results.find(
+    (result): result is PromiseRejectedResult => result.status === "rejected"
+  );
+  if (failed) {
+    for (const result of results) {
+      if (result.status === "fulfilled") result.value.terminate();
+    }
+    throw failed.reason;
+  }
+  return results.map(
+    (result) => (result as PromiseFulfilledResult<DenoHTTPWorker>).value
+  );
+};
+
 export interface DenoHTTPWorker {
   /**
    * Terminate the worker. This kills the process with SIGKILL if it is still
-   * running, closes the http2 connection, and deletes the socket file.
+   * running, closes the http2 connection, and deletes the socket file. Calling
+   * it again, or after the process exited, does nothing; the "exit" event is
+   * only emitted once.
    */
   terminate(): void;
 
+  /**
+   * True once the worker has been terminated or its process has exited.
+   */
+  get terminated(): boolean;
+
+  /**
+   * Waits for the Deno process to exit on its own and resolves with how it
+   * ended, along with the last output it wrote to stderr. Meant for scripts
+   * that are run once to completion. The code is null if the process was
+   * killed by a signal.
+   */
+  runToCompletion(): Promise<{
+    code: number | null;
+    signal: string;
+    stderrTail: string;
+  }>;
+
+  /**
+   * Stops managing the Deno process without killing it, for handing it off to
+   * another owner. The worker can't be used afterwards, the socket file is
+   * left in place and no "exit" event is emitted. The process's output is no
+   * longer collected: it is read and discarded from then on, so that the
+   * process doesn't block on writing it, and the stdout and stderr streams
+   * end. Returns what the new owner needs to reach and control the process.
+   */
+  detach(): {
+    pid: number | undefined;
+    socketFile: string;
+    controlSocketFile?: string;
+  };
+
+  /**
+   * Terminates the worker and resolves once the process has exited, the
+   * socket file has been removed and the process output is no longer being
+   * read. Prefer this over terminate() when you can wait, as nothing belonging
+   * to the worker is left running once it resolves. The worker's stdout and
+   * stderr streams are ended.
+   */
+  close(): Promise<void>;
+
   /**
    * Gracefully shuts down the worker process and waits for any unresolved
-   * promises to exit.
+   * promises to exit. If gracefulShutdownTimeout is set the process is killed
+   * once the timeout elapses.
    */
   shutdown(): void;
 
+  /**
+   * Like shutdown, but waits up to grace milliseconds for the process to
+   * exit. Resolves with the exit code and signal if it did, or with undefined
+   * if it is still running, so the caller can decide whether to wait longer or
+   * to terminate it. The process is not killed by this method.
+   */
+  shutdownAndWait(
+    grace: number
+  ): Promise<{ code: number; signal: string } | undefined>;
+
   /**
    * request calls http.request but patches the options to work with our
-   * connection pool and safely handle rewriting various headers.
+   * connection pool and safely handle rewriting various headers. Throws an
+   * InvalidUrlError if url is not an absolute URL.
+   *
+   * The callback is called as soon as the response headers arrived, before
+   * any of the body was read, so the status and headers can be used to decide
+   * how to read the body, or whether to read it at all. The body isn't
+   * buffered: read it from the response stream, or call resume() or destroy()
+   * on it to discard it.
+   *
+   * Pass an AbortSignal as options.signal to cancel the request. Aborting, or
+   * calling destroy() on the returned request before the response ended,
+   * closes the connection instead of returning it to the pool, so the
+   * handler's `request.signal` is aborted as well.
    */
   request(
     url: string | URL,
@@ -269,101 +1517,1167 @@ export interface DenoHTTPWorker {
     callback: (response: http.IncomingMessage) => void
   ): http.ClientRequest;
 
-  get stdout(): Readable;
+  /**
+   * registerScript loads another default-export module into the running Deno
+   * process under the given route key. Requests sent with requestTo and the
+   * same key are handled by that module instead of the worker's own script.
+   * Registering a key again replaces the previous module. Rejects with a
+   * ScriptRegistrationError if the script can't be loaded. Requires the
+   * controlSocket option, so that requests can't register scripts.
+   */
+  registerScript(key: string, source: string): Promise<void>;
 
-  get stderr(): Readable;
+  /**
+   * requestTo is like request but the request is handled by the script that
+   * was registered under key with registerScript. The response has status
+   * 404 if no script is registered under key.
+   */
+  requestTo(
+    key: string,
+    url: string | URL,
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void
+  ): http.ClientRequest;
 
   /**
-   * Adds the given listener for the "exit" event.
+   * requestWithHeaderList is like request but takes the headers as a list of
+   * [name, value] pairs. The headers are sent in the given order and repeated
+   * names are sent as separate headers rather than being merged.
    */
-  addEventListener(type: "exit", listener: OnExitListener): void;
-}
+  requestWithHeaderList(
+    url: string | URL,
+    headers: ReadonlyArray<readonly [string, string]>,
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void
+  ): http.ClientRequest;
 
-class denoHTTPWorker {
-  #onexitListeners: OnExitListener[];
-  #process: MinimalChildProcess;
-  #socketFile: string;
-  #stderr: Readable;
-  #stdout: Readable;
-  #terminated: Boolean = false;
-  #agent: http.Agent;
+  /**
+   * requestCollect sends a request with an optional body and resolves with the
+   * status, headers and fully buffered body once the response has ended. Like
+   * request, it can be cancelled with options.signal. Rejects with a
+   * PermissionDeniedError if the script failed because it lacks a permission.
+   */
+  requestCollect(
+    url: string | URL,
+    options?: http.RequestOptions,
+    body?: RequestBody
+  ): Promise<CollectedResponse>;
 
-  constructor(
-    socketFile: string,
-    process: MinimalChildProcess,
-    stdout: Readable,
-    stderr: Readable
-  ) {
-    this.#onexitListeners = [];
-    this.#process = process;
-    this.#socketFile = socketFile;
-    this.#stderr = stderr;
-    this.#stdout = stdout;
-    this.#agent = new http.Agent({ keepAlive: true });
-  }
+  /**
+   * requestJSON is like requestCollect but parses the response body as JSON.
+   * The status code is returned along with the body so that JSON error
+   * responses can be handled too. Rejects with a ResponseDecodeError if the
+   * body isn't valid JSON.
+   */
+  requestJSON(
+    url: string | URL,
+    options?: http.RequestOptions,
+    body?: RequestBody
+  ): Promise<JSONResponse>;
 
-  _terminate(code?: number, signal?: string) {
-    if (this.#terminated) {
-      return;
-    }
-    this.#terminated = true;
-    if (this.#process && this.#process.exitCode === null) {
-      forceKill(this.#process.pid!);
-    }
-    this.#agent.destroy();
-    fs.rm(this.#socketFile).catch(() => {});
-    for (const onexit of this.#onexitListeners) {
-      onexit(code ?? 1, signal ?? "");
-    }
-  }
+  /**
+   * requestText is like requestCollect but decodes the response body as text,
+   * using the charset of the Content-Type header or UTF-8 if there is none.
+   * Rejects with a ResponseDecodeError if the charset is unknown or the body
+   * isn't valid in it.
+   */
+  requestText(
+    url: string | URL,
+    options?: http.RequestOptions,
+    body?: RequestBody
+  ): Promise<TextResponse>;
 
-  terminate() {
-    this._terminate();
-  }
+  /**
+   * requestJSONValue is like requestJSON but sends value serialized as JSON
+   * with a "Content-Type: application/json" header, unless options sets a
+   * content type of its own. The method defaults to POST.
+   */
+  requestJSONValue(
+    url: string | URL,
+    value: unknown,
+    options?: http.RequestOptions
+  ): Promise<JSONResponse>;
 
-  shutdown() {
-    this.#process.kill("SIGINT");
-  }
+  /**
+   * requestNDJSON sends a request and yields the values of a newline-delimited
+   * JSON response as the lines arrive, so that streamed records can be handled
+   * without buffering the whole response. Blank lines are skipped. Stopping
+   * the iteration early aborts the response.
+   */
+  requestNDJSON(
+    url: string | URL,
+    options?: http.RequestOptions,
+    body?: RequestBody
+  ): AsyncGenerator<any, void, undefined>;
 
-  request(
+  /**
+   * requestWithRetry is like requestCollect but retries the request according
+   * to policy when it fails or the response has a retryable status. When the
+   * attempts run out, the last response is returned or the last error thrown.
+   * The body is sent again on every attempt, which is why it has to be a
+   * string or buffer rather than a stream.
+   */
+  requestWithRetry(
     url: string | URL,
     options: http.RequestOptions,
-    callback: (response: http.IncomingMessage) => void
-  ): http.ClientRequest {
-    options.headers = options.headers || {};
+    body: string | Uint8Array | undefined,
+    policy: RetryPolicy
+  ): Promise<CollectedResponse>;
 
-    // TODO: ensure these are handled with the correct casing?
-    delete options.headers["x-deno-worker-host"];
-    delete options.headers["x-deno-worker-connection"];
+  /**
+   * requestBatch sends the requests with requestCollect, at most concurrency
+   * of them at a time, and resolves with their outcomes in the same order once
+   * all of them settled. A failed request doesn't stop the others. By default
+   * all requests are sent at once.
+   */
+  requestBatch(
+    requests: BatchRequest[],
+    concurrency?: number
+  ): Promise<PromiseSettledResult<CollectedResponse>[]>;
 
-    // NodeJS will send both the host and the connection headers
-    // (https://nodejs.org/api/http.html#new-agentoptions). We don't want these
-    // to make it to Deno unless they are explicitly set by the user. So store
+  /**
+   * requestWithInfo is like requestCollect but also resolves with timing
+   * information about the request, which helps to tell where latency comes
+   * from.
+   */
+  requestWithInfo(
+    url: string | URL,
+    options?: http.RequestOptions,
+    body?: RequestBody
+  ): Promise<{ response: CollectedResponse; info: ConnectionInfo }>;
+
+  get stdout(): Readable;
+
+  get stderr(): Readable;
+
+  /**
+   * Returns a snapshot of the lines the Deno process wrote to stdout and
+   * stderr since it started, oldest first, limited by the capturedLogLines
+   * and capturedLogOverflow options. Unlike the stdout and stderr streams this
+   * includes output written before the call.
+   */
+  capturedLogs(): LogLine[];
+
+  /**
+   * The number of requests that have been started but whose responses have
+   * not yet completed.
+   */
+  get inFlight(): number;
+
+  /**
+   * How long ago the Deno process was spawned, in milliseconds. Useful to
+   * recycle workers once they reach a certain age.
+   */
+  get uptime(): number;
+
+  /**
+   * The peak resident set size of the Deno process in bytes so far. It is
+   * sampled from /proc about once a second, so it is undefined on platforms
+   * other than Linux and until the first sample was taken. Useful to pick a
+   * memory limit.
+   */
+  get peakRSS(): number | undefined;
+
+  /**
+   * The run flags the Deno process was started with, after the socket paths,
+   * allowReadPaths and allowWritePaths were merged into the permission flags.
+   * Useful to log or debug the permissions the script actually has.
+   */
+  get effectiveRunFlags(): string[];
+
+  /**
+   * The path of the Unix socket the Deno process listens on. Workers only
+   * listen on Unix sockets, so this is the address to give external tools,
+   * for example `curl --unix-socket`. Requests sent to it directly have to
+   * set the X-Deno-Worker-URL header to the URL the script should see, since
+   * the bootstrap answers requests without it itself, as warm requests.
+   */
+  get socketPath(): string;
+
+  /**
+   * Resolves once there are no requests in flight. Resolves immediately if the
+   * worker is already idle.
+   */
+  waitIdle(): Promise<void>;
+
+  /**
+   * Closes all pooled connections to the worker so that the next request
+   * opens a new one, for example when the pool is suspected to hold broken
+   * connections. Requests in flight on the old connections still finish, and
+   * those connections are closed afterwards instead of being reused.
+   */
+  resetConnections(): void;
+
+  /**
+   * Calls f with the underlying child process and returns its result, or
+   * returns undefined without calling f once the worker has terminated. This
+   * is meant for diagnostics; killing the process or consuming its streams
+   * from f can interfere with the worker.
+   */
+  withProcess<R>(f: (process: MinimalChildProcess) => R): R | undefined;
+
+  /**
+   * Loads the worker's script again and sends new requests to the reloaded
+   * module. Requests that are in flight finish on the old one. Only the
+   * script's own module is reloaded; modules it imports are cached. Requires
+   * the controlSocket option.
+   */
+  reloadScript(): Promise<void>;
+
+  /**
+   * Stops the Deno process from accepting requests and resolves once the
+   * requests it is handling have finished. The process keeps running until
+   * it is terminated. Requires the controlSocket option.
+   */
+  drain(): Promise<void>;
+
+  /**
+   * Reports request and memory statistics from inside the Deno process.
+   * Requires the controlSocket option.
+   */
+  stats(): Promise<WorkerStats>;
+
+  /**
+   * Adds the given listener for the "exit" event.
+   */
+  addEventListener(type: "exit", listener: OnExitListener): void;
+
+  /**
+   * Removes a listener that was added with addEventListener, so that it is no
+   * longer called and no longer kept alive by the worker.
+   */
+  removeEventListener(type: "exit", listener: OnExitListener): void;
+
+  /**
+   * Replaces the Deno process behind this worker without interrupting callers.
+   * A new process is started with the given script and warmed up, after
+   * which new requests go to it. The given options are applied over the ones
+   * the worker was created or last replaced with. Requests that were already
+   * sent to the old process finish there before it is terminated. Scripts
+   * added with registerScript are not carried over, and the stdout and stderr
+   * getters return the new process's streams.
+   */
+  replace(
+    script: string | URL,
+    options?: Partial<DenoWorkerOptions>
+  ): Promise<void>;
+}
+
+// The worker that currently owns each process. This changes when a worker's
+// process is replaced.
+const processOwners = new WeakMap<MinimalChildProcess, denoHTTPWorker>();
+
+// Responses that the bootstrap marked as failed because of a missing
+// permission. The marker header itself may be filtered out by the
+// responseHeaderAllowlist option before requestCollect looks at it.
+const permissionDeniedResponses = new WeakSet<http.IncomingMessage>();
+
+// When each connection the agent opened was established. The agent only hands
+// a socket to a request once it is connected, so the request can't observe
+// the connect event itself.
+const socketConnectedAt = new WeakMap<net.Socket, number>();
+
+class denoHTTPWorker {
+  #onexitListeners: OnExitListener[];
+  #process: MinimalChildProcess;
+  #socketFile: string;
+  #controlSocketFile?: string;
+  #stderr: PassThrough;
+  #stdout: PassThrough;
+  #terminated = false;
+  #agent: workerAgent;
+  #connectionFailures = 0;
+  #peakRSS?: number;
+  #rssTimer?: NodeJS.Timeout;
+  #inFlight = 0;
+  #idleWaiters: (() => void)[] = [];
+  #requests = new Set<http.ClientRequest>();
+  #stderrTail: outputTail;
+  #capturedLogs: LogLine[];
+  #exited: Promise<ExitStatus>;
+  #resolveExited: (status: ExitStatus) => void = () => {};
+  #processExit: Promise<void>;
+  #outputReaders: OutputReader[];
+  #closed: Promise<void> = Promise.resolve();
+  #socketRemoved: Promise<void> = Promise.resolve();
+  #options: DenoWorkerOptions;
+  // The options as they were passed in, which replace starts from.
+  #createOptions: Partial<DenoWorkerOptions>;
+  #shutdownTimer?: NodeJS.Timeout;
+  #forced = false;
+  #shuttingDown = false;
+  #startedAt: number;
+
+  constructor(
+    socketFile: string,
+    controlSocketFile: string | undefined,
+    process: MinimalChildProcess,
+    stdout: PassThrough,
+    stderr: PassThrough,
+    stderrTail: outputTail,
+    capturedLogs: LogLine[],
+    processExit: Promise<void>,
+    outputReaders: OutputReader[],
+    startedAt: number,
+    options: DenoWorkerOptions,
+    createOptions: Partial<DenoWorkerOptions>
+  ) {
+    this.#processExit = processExit;
+    this.#outputReaders = outputReaders;
+    this.#startedAt = startedAt;
+    this.#options = options;
+    this.#createOptions = createOptions;
+    this.#onexitListeners = [];
+    this.#process = process;
+    this.#socketFile = socketFile;
+    this.#controlSocketFile = controlSocketFile;
+    this.#stderr = stderr;
+    this.#stdout = stdout;
+    this.#stderrTail = stderrTail;
+    this.#capturedLogs = capturedLogs;
+    this.#agent = this.#newAgent();
+    this.#exited = new Promise((resolve) => (this.#resolveExited = resolve));
+    processOwners.set(process, this);
+    if (os.platform() === "linux") {
+      this.#sampleRSS();
+      this.#rssTimer = setInterval(
+        () => this.#sampleRSS(),
+        RSS_SAMPLE_INTERVAL_MS
+      );
+      this.#rssTimer.unref();
+    }
+  }
+
+  // VmHWM is the kernel's own peak RSS, so only growth since the last sample
+  // is missed when the process exits.
+  async #sampleRSS() {
+    const pid = this.#process.pid;
+    if (pid === undefined) return;
+    try {
+      const status = await fs.readFile(`/proc/${pid}/status`, "utf8");
+      const match = /^VmHWM:\s+(\d+) kB$/m.exec(status);
+      if (match) {
+        this.#peakRSS = Math.max(this.#peakRSS ?? 0, Number(match[1]) * 1024);
+      }
+    } catch {
+      // The process already exited.
+    }
+  }
+
+  _terminate(code?: number, signal?: string) {
+    if (this.#terminated) {
+      // The process exiting after terminate still settles its exit status.
+      if (code !== undefined) {
+        this.#resolveExited({ code, signal: signal ?? "" });
+      }
+      return;
+    }
+    this.#terminated = true;
+    if (code !== undefined) {
+      // The process exited on its own, fail whatever is still waiting on it
+      // with an error that says so.
+      const status = { code, signal: signal ?? "" };
+      this.#resolveExited(status);
+      for (const req of this.#requests) {
+        req.destroy(this.#exitedDuringRequestError(status));
+      }
+    }
+    if (this.#process && this.#process.exitCode === null) {
+      forceKill(this.#process.pid!);
+    }
+    this.#agent.destroy();
+    if (!this.#options.keepSocketOnExit) {
+      this.#socketRemoved = removeSocketFiles(
+        this.#controlSocketFile
+          ? [this.#socketFile, this.#controlSocketFile]
+          : [this.#socketFile]
+      );
+    }
+    this.#closed = this.#reclaim();
+    clearTimeout(this.#shutdownTimer);
+    clearInterval(this.#rssTimer);
+    const details = {
+      forced: this.#forced || code === undefined,
+      peakRSS: this.#peakRSS,
+    };
+    for (const onexit of this.#onexitListeners) {
+      onexit(code ?? 1, signal ?? "", details);
+    }
+  }
+
+  terminate() {
+    this.#shuttingDown = true;
+    this._terminate();
+  }
+
+  get terminated() {
+    return this.#terminated;
+  }
+
+  async runToCompletion() {
+    const status = await this.#exited;
+    await this.#closed;
+    return { ...status, stderrTail: this.#stderrTail.toString() };
+  }
+
+  detach() {
+    if (!this.#terminated) {
+      this.#terminated = true;
+      this.#agent.destroy();
+      clearTimeout(this.#shutdownTimer);
+      clearInterval(this.#rssTimer);
+      // Don't keep this process alive on behalf of a process we no longer own.
+      this.#process.unref?.();
+      processOwners.delete(this.#process);
+      for (const { lines } of this.#outputReaders) {
+        lines.close();
+      }
+      for (const [input, output] of [
+        [this.#process.stdout, this.#stdout],
+        [this.#process.stderr, this.#stderr],
+      ] as const) {
+        input?.unpipe(output).resume();
+        if (input instanceof net.Socket) input.unref();
+        output.end();
+      }
+    }
+    return {
+      pid: this.#process.pid,
+      socketFile: this.#socketFile,
+      controlSocketFile: this.#controlSocketFile,
+    };
+  }
+
+  async close() {
+    this._terminate();
+    // Output that nobody reads would otherwise hold the streams open.
+    for (const [input, output] of [
+      [this.#process.stdout, this.#stdout],
+      [this.#process.stderr, this.#stderr],
+    ] as const) {
+      input?.unpipe(output).resume();
+      output.end();
+    }
+    await this.#closed;
+  }
+
+  // Waits for the process and its output to be gone. The output is given a
+  // moment to drain, but a subprocess of the script can inherit stdout and hold
+  // it open, so we stop reading rather than wait for it indefinitely.
+  async #reclaim() {
+    await this.#processExit;
+    await this.#socketRemoved;
+    await Promise.race([
+      Promise.all(this.#outputReaders.map(({ closed }) => closed)),
+      new Promise((resolve) => setTimeout(resolve, OUTPUT_DRAIN_TIMEOUT_MS)),
+    ]);
+    for (const { lines } of this.#outputReaders) {
+      lines.close();
+    }
+    this.#process.stdout?.destroy();
+    this.#process.stderr?.destroy();
+    this.#stdout.end();
+    this.#stderr.end();
+  }
+
+  shutdown() {
+    this.#shuttingDown = true;
+    this.#process.kill("SIGINT");
+    const timeout = this.#options.gracefulShutdownTimeout;
+    if (timeout !== undefined && this.#shutdownTimer === undefined) {
+      this.#shutdownTimer = setTimeout(() => {
+        this.#forced = true;
+        forceKill(this.#process.pid!);
+      }, timeout);
+    }
+  }
+
+  async shutdownAndWait(grace: number) {
+    this.shutdown();
+    let timer: NodeJS.Timeout | undefined;
+    const status = await Promise.race([
+      this.#exited,
+      new Promise<undefined>((resolve) => {
+        timer = setTimeout(resolve, grace);
+      }),
+    ]);
+    clearTimeout(timer);
+    return status && { ...status };
+  }
+
+  request(
+    url: string | URL,
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void
+  ): http.ClientRequest {
+    return this.#request(url, options, callback);
+  }
+
+  requestTo(
+    key: string,
+    url: string | URL,
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void
+  ): http.ClientRequest {
+    return this.#request(url, options, callback, key);
+  }
+
+  #request(
+    url: string | URL,
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void,
+    route?: string
+  ): http.ClientRequest {
+    options.headers = withDefaultHeaders(
+      options.headers || {},
+      this.#defaultHeaders()
+    );
+    const intercepted = this.#intercept(url, options);
+    const target = intercepted.url;
+    options = intercepted.options;
+    options.headers = options.headers || {};
+
+    // Only the worker sets the headers it uses to talk to the bootstrap
+    // script, whatever casing the caller used.
+    for (const name of Object.keys(options.headers)) {
+      if (isInternalHeader(name)) delete options.headers[name];
+    }
+
+    // NodeJS will send both the host and the connection headers
+    // (https://nodejs.org/api/http.html#new-agentoptions). We don't want these
+    // to make it to Deno unless they are explicitly set by the user. So store
     // them to reconstruct on the other size.
     if (options.headers.host)
       options.headers["X-Deno-Worker-Host"] = options.headers.host;
+    else if (this.#options.preserveHost)
+      options.headers["X-Deno-Worker-Host"] = target.host;
     if (options.headers.connection)
       options.headers["X-Deno-Worker-Connection"] = options.headers.connection;
 
     options.headers = {
       ...options.headers,
-      "X-Deno-Worker-URL": typeof url === "string" ? url : url.toString(),
+      "X-Deno-Worker-URL": target.href,
+    };
+    if (route !== undefined) options.headers["X-Deno-Worker-Route"] = route;
+    if (this.#options.requestTimeout !== undefined) {
+      options.headers["X-Deno-Worker-Deadline-Ms"] = String(
+        this.#options.requestTimeout
+      );
+    }
+    return this.#withTimeout(
+      this.#send(options, this.#interceptResponse(intercepted, callback))
+    );
+  }
+
+  async registerScript(key: string, source: string): Promise<void> {
+    await this.#control("register", {
+      params: { key },
+      body: source,
+      failed: (reason) => new ScriptRegistrationError(key, reason),
+    });
+  }
+
+  requestWithHeaderList(
+    url: string | URL,
+    headers: ReadonlyArray<readonly [string, string]>,
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void
+  ): http.ClientRequest {
+    // Headers set by the interceptor are sent after the given ones.
+    const intercepted = this.#intercept(url, { ...options, headers: {} });
+    const target = intercepted.url;
+    const extraHeaders: [string, string][] = [];
+    for (const [name, value] of Object.entries(
+      intercepted.options.headers || {}
+    )) {
+      if (value === undefined) continue;
+      for (const v of Array.isArray(value) ? value : [value]) {
+        extraHeaders.push([name, String(v)]);
+      }
+    }
+    const rawHeaders: string[] = [];
+    const names = new Set(headers.map(([name]) => name.toLowerCase()));
+    const defaultHeaders = Object.entries(this.#defaultHeaders()).filter(
+      ([name]) => !names.has(name.toLowerCase())
+    );
+    const allHeaders = [...headers, ...defaultHeaders, ...extraHeaders];
+    if (
+      this.#options.preserveHost &&
+      !allHeaders.some(([name]) => name.toLowerCase() === "host")
+    ) {
+      rawHeaders.push("X-Deno-Worker-Host", target.host);
+    }
+    for (const [name, value] of allHeaders) {
+      if (isInternalHeader(name)) continue;
+      switch (name.toLowerCase()) {
+        case "host":
+          rawHeaders.push("X-Deno-Worker-Host", value);
+          break;
+        case "connection":
+          rawHeaders.push("X-Deno-Worker-Connection", value);
+          break;
+        default:
+          rawHeaders.push(name, value);
+      }
+    }
+    rawHeaders.push("X-Deno-Worker-URL", target.href);
+    if (this.#options.requestTimeout !== undefined) {
+      rawHeaders.push(
+        "X-Deno-Worker-Deadline-Ms",
+        String(this.#options.requestTimeout)
+      );
+    }
+    // http.request sends a flat [name, value, ...] array as is, in order and
+    // including repeated names.
+    return this.#withTimeout(
+      this.#send(
+        { ...intercepted.options, headers: rawHeaders as any },
+        this.#interceptResponse(intercepted, callback)
+      )
+    );
+  }
+
+  // Aborts the request if it hasn't finished within the requestTimeout
+  // option. Warm and control requests don't get a timeout.
+  #withTimeout(req: http.ClientRequest): http.ClientRequest {
+    const timeout = this.#options.requestTimeout;
+    if (timeout !== undefined) {
+      const timer = setTimeout(
+        () => req.destroy(new RequestTimeoutError(timeout)),
+        timeout
+      );
+      req.once("close", () => clearTimeout(timer));
+    }
+    return req;
+  }
+
+  // The defaultHeaders option together with the trace context headers.
+  #defaultHeaders(): Record<string, string> {
+    const headers = { ...this.#options.defaultHeaders };
+    const trace = this.#options.traceContext?.();
+    if (trace) {
+      headers.traceparent = trace.traceparent;
+      if (trace.tracestate !== undefined) {
+        headers.tracestate = trace.tracestate;
+      }
+    }
+    return headers;
+  }
+
+  #intercept(url: string | URL, options: http.RequestOptions) {
+    const request: InterceptedRequest = { url: this.#target(url), options };
+    this.#options.requestInterceptor?.(request);
+    return request;
+  }
+
+  #interceptResponse(
+    request: InterceptedRequest,
+    callback: (response: http.IncomingMessage) => void
+  ) {
+    const interceptor = this.#options.responseInterceptor;
+    const allowlist = this.#options.responseHeaderAllowlist;
+    return (response: http.IncomingMessage) => {
+      if (response.headers["x-deno-worker-error"] === "PermissionDenied") {
+        permissionDeniedResponses.add(response);
+      }
+      // Only the warm request checks the protocol version.
+      removeResponseHeaders(
+        response,
+        (name) => name === "x-deno-worker-protocol"
+      );
+      interceptor?.(response, request);
+      if (allowlist) {
+        const allowed = new Set(allowlist.map((name) => name.toLowerCase()));
+        removeResponseHeaders(response, (name) => !allowed.has(name));
+      }
+      callback(response);
     };
-    url = "http://deno";
+  }
+
+  #target(url: string | URL): URL {
+    const target = parseURL(url);
+    const basePath = normalizeBasePath(this.#options.basePath);
+    if (!basePath) {
+      return target;
+    }
+    const prefixed = new URL(target);
+    prefixed.pathname = basePath + target.pathname;
+    return prefixed;
+  }
+
+  #send(
+    options: http.RequestOptions,
+    callback: (response: http.IncomingMessage) => void
+  ): http.ClientRequest {
     options.agent = this.#agent;
     options.socketPath = this.#socketFile;
-    return http.request(url, options, callback);
+    // Node only frames the body of a DELETE or OPTIONS request if it has a
+    // content-length header. Otherwise a body written to it never reaches the
+    // handler, so use chunked encoding like for other methods. This has to be
+    // a header: headers given as a list are written out when the request is
+    // created, before anything else about it could be changed.
+    const method = options.method?.toUpperCase();
+    if (method === "DELETE" || method === "OPTIONS") {
+      options.headers = withChunkedEncoding(options.headers);
+    }
+    const req = http.request("http://deno", options, callback);
+    this.#trackInFlight(req);
+    this.#trackConnectionFailures(req);
+    return req;
+  }
+
+  #newAgent() {
+    const agent = new workerAgent({ keepAlive: true });
+    agent.connectTimeout = this.#options.connectTimeout;
+    return agent;
+  }
+
+  #trackConnectionFailures(req: http.ClientRequest) {
+    let responded = false;
+    req.once("response", () => {
+      responded = true;
+      this.#connectionFailures = 0;
+    });
+    req.once("error", (err: NodeJS.ErrnoException) => {
+      if (responded || !isConnectionError(err)) {
+        return;
+      }
+      this.#connectionFailures++;
+      if (
+        this.#connectionFailures >= this.#options.connectionFailureThreshold &&
+        !this.#terminated
+      ) {
+        // Replace the pool rather than reusing connections that may be broken.
+        this.resetConnections();
+      }
+    });
+  }
+
+  #trackInFlight(req: http.ClientRequest) {
+    this.#inFlight++;
+    this.#requests.add(req);
+    // "close" is emitted once the response has been fully consumed or the
+    // request failed, whichever comes first.
+    req.once("close", () => {
+      this.#inFlight--;
+      this.#requests.delete(req);
+      if (this.#inFlight === 0) {
+        const waiters = this.#idleWaiters;
+        this.#idleWaiters = [];
+        for (const resolve of waiters) resolve();
+      }
+    });
+  }
+
+  requestCollect(
+    url: string | URL,
+    options: http.RequestOptions = {},
+    body?: RequestBody
+  ): Promise<CollectedResponse> {
+    return this.#collect(url, options, body);
+  }
+
+  requestJSONValue(
+    url: string | URL,
+    value: unknown,
+    options: http.RequestOptions = {}
+  ): Promise<JSONResponse> {
+    const headers = { ...options.headers };
+    const names = Object.keys(headers).map((name) => name.toLowerCase());
+    if (!names.includes("content-type")) {
+      headers["content-type"] = "application/json";
+    }
+    return this.requestJSON(
+      url,
+      { method: "POST", ...options, headers },
+      JSON.stringify(value)
+    );
+  }
+
+  async *requestNDJSON(
+    url: string | URL,
+    options: http.RequestOptions = {},
+    body?: RequestBody
+  ): AsyncGenerator<any, void, undefined> {
+    const resp = await new Promise<http.IncomingMessage>((resolve, reject) => {
+      const req = this.request(url, options, resolve);
+      req.on("error", (err) => this.#attributeError(err).then(reject));
+      writeBody(req, body);
+    });
+    const parse = (line: string) => {
+      try {
+        return JSON.parse(line);
+      } catch (err) {
+        throw new ResponseDecodeError(
+          resp.statusCode as number,
+          Buffer.from(line),
+          (err as Error).message
+        );
+      }
+    };
+    resp.setEncoding("utf8");
+    let buffered = "";
+    try {
+      for await (const chunk of resp) {
+        buffered += chunk;
+        let newline: number;
+        while ((newline = buffered.indexOf("\n")) !== -1) {
+          const line = buffered.slice(0, newline);
+          buffered = buffered.slice(newline + 1);
+          if (line.trim() !== "") yield parse(line);
+        }
+      }
+    } catch (err) {
+      throw await this.#attributeError(err as Error);
+    } finally {
+      // The caller may stop reading before the end of the stream.
+      if (!resp.complete) resp.destroy();
+    }
+    if (buffered.trim() !== "") yield parse(buffered);
+  }
+
+  async requestJSON(
+    url: string | URL,
+    options: http.RequestOptions = {},
+    body?: RequestBody
+  ): Promise<JSONResponse> {
+    const resp = await this.#collect(url, options, body);
+    let json: any;
+    try {
+      json = JSON.parse(resp.body.toString());
+    } catch (err) {
+      throw new ResponseDecodeError(
+        resp.statusCode,
+        resp.body,
+        (err as Error).message
+      );
+    }
+    return { statusCode: resp.statusCode, headers: resp.headers, body: json };
+  }
+
+  async requestText(
+    url: string | URL,
+    options: http.RequestOptions = {},
+    body?: RequestBody
+  ): Promise<TextResponse> {
+    const resp = await this.#collect(url, options, body);
+    const charset =
+      /;\s*charset\s*=\s*"?([^";\s]+)/i.exec(
+        resp.headers["content-type"] ?? ""
+      )?.[1] ?? "utf-8";
+    let text: string;
+    try {
+      text = new TextDecoder(charset, { fatal: true }).decode(resp.body);
+    } catch (err) {
+      throw new ResponseDecodeError(
+        resp.statusCode,
+        resp.body,
+        `${charset}: ${(err as Error).message}`
+      );
+    }
+    return { statusCode: resp.statusCode, headers: resp.headers, body: text };
+  }
+
+  async requestWithRetry(
+    url: string | URL,
+    options: http.RequestOptions,
+    body: string | Uint8Array | undefined,
+    policy: RetryPolicy
+  ): Promise<CollectedResponse> {
+    const retryError = policy.retryError ?? isConnectionError;
+    const retryStatus = (status: number) =>
+      policy.retryStatuses
+        ? policy.retryStatuses.includes(status)
+        : status >= 500 && status < 600;
+    let wait = policy.backoff ?? 50;
+    for (let attempt = 1; ; attempt++) {
+      const last = attempt >= policy.maxAttempts || this.#terminated;
+      try {
+        // Each attempt gets its own copy since request rewrites the headers.
+        const resp = await this.#collect(
+          url,
+          { ...options, headers: { ...options.headers } },
+          body
+        );
+        if (last || !retryStatus(resp.statusCode)) {
+          return resp;
+        }
+      } catch (err) {
+        if (last || !retryError(err as Error)) {
+          throw err;
+        }
+      }
+      await new Promise((resolve) => setTimeout(resolve, wait));
+      wait *= policy.backoffFactor ?? 2;
+    }
+  }
+
+  async requestBatch(
+    requests: BatchRequest[],
+    concurrency = Math.max(requests.length, 1)
+  ): Promise<PromiseSettledResult<CollectedResponse>[]> {
+    if (!(Number.isInteger(concurrency) && concurrency > 0)) {
+      throw new InvalidConfigError("concurrency must be a positive integer");
+    }
+    const results: PromiseSettledResult<CollectedResponse>[] = [];
+    let next = 0;
+    const send = async () => {
+      while (next < requests.length) {
+        const index = next++;
+        const { url, options = {}, body } = requests[index]!;
+        try {
+          const value = await this.#collect(url, options, body);
+          results[index] = { status: "fulfilled", value };
+        } catch (reason) {
+          results[index] = { status: "rejected", reason };
+        }
+      }
+    };
+    await Promise.all(
+      Array.from({ length: Math.min(concurrency, requests.length) }, send)
+    );
+    return results;
+  }
+
+  async requestWithInfo(
+    url: string | URL,
+    options: http.RequestOptions = {},
+    body?: RequestBody
+  ): Promise<{ response: CollectedResponse; info: ConnectionInfo }> {
+    const start = performance.now();
+    const info: ConnectionInfo = {
+      reusedConnection: false,
+      connectTime: 0,
+      timeToFirstByte: 0,
+    };
+    const response = await this.#collect(url, options, body, (req) => {
+      req.on("socket", (socket) => {
+        info.reusedConnection = req.reusedSocket;
+        if (!req.reusedSocket) {
+          const connectedAt =
+            socketConnectedAt.get(socket) ?? performance.now();
+          info.connectTime = connectedAt - start;
+        }
+      });
+      req.on("response", () => {
+        info.timeToFirstByte = performance.now() - start;
+      });
+    });
+    return { response, info };
+  }
+
+  #collect(
+    url: string | URL,
+    options: http.RequestOptions,
+    body?: RequestBody,
+    onRequest?: (req: http.ClientRequest) => void
+  ): Promise<CollectedResponse> {
+    return new Promise((resolve, reject) => {
+      const limit = this.#options.maxResponseBytes;
+      let responded = false;
+      let received = 0;
+      const req = this.request(url, options, (resp) => {
+        responded = true;
+        const chunks: Buffer[] = [];
+        let tooLarge = false;
+        resp.on("error", (err) => {
+          // The request's own error says why it was cut short.
+          if (tooLarge || options.signal?.aborted) return;
+          this.#incompleteResponseError(err, received).then(reject);
+        });
+        resp.on("data", (chunk) => {
+          received += chunk.length;
+          if (limit !== undefined && received > limit) {
+            tooLarge = true;
+            req.destroy(new ResponseTooLargeError(limit));
+            return;
+          }
+          chunks.push(chunk);
+        });
+        resp.on("end", () => {
+          if (permissionDeniedResponses.has(resp)) {
+            const body = Buffer.concat(chunks).toString();
+            reject(withPhase(new PermissionDeniedError(body), "request"));
+            return;
+          }
+          const collected: CollectedResponse = {
+            statusCode: resp.statusCode as number,
+            statusMessage: resp.statusMessage as string,
+            headers: resp.headers,
+            body: Buffer.concat(chunks),
+          };
+          const toError = this.#options.statusErrors?.[collected.statusCode];
+          if (toError) {
+            // A mapper that throws fails the request with what it threw.
+            try {
+              reject(toError(collected));
+            } catch (err) {
+              reject(err);
+            }
+            return;
+          }
+          resolve(collected);
+        });
+      });
+      req.on("error", (err) => {
+        // A crash while the body streams in may fail the request before the
+        // response notices.
+        if (responded && err instanceof WorkerExitedDuringRequestError) {
+          this.#incompleteResponseError(err, received).then(reject);
+        } else {
+          this.#attributeError(err).then(reject);
+        }
+      });
+      onRequest?.(req);
+      writeBody(req, body);
+    });
+  }
+
+  async #incompleteResponseError(err: Error, received: number) {
+    const attributed = await this.#attributeError(err);
+    return withPhase(
+      new IncompleteResponseError(
+        received,
+        attributed instanceof WorkerExitedDuringRequestError
+          ? attributed
+          : undefined
+      ),
+      this.#shuttingDown ? "shutdown" : "request"
+    );
+  }
+
+  #exitedDuringRequestError(status: ExitStatus) {
+    return new WorkerExitedDuringRequestError(
+      status.code,
+      status.signal,
+      this.#stderrTail.toString()
+    );
+  }
+
+  // Connection errors are often the first sign of the process crashing, the
+  // exit event can arrive slightly later. Give it a moment so that we can
+  // report the crash rather than a bare socket error.
+  async #attributeError(err: Error): Promise<Error> {
+    // A socket that vanished may also be the result of the process exiting.
+    const attributable =
+      !(err instanceof DenoWorkerError) || err instanceof SocketGoneError;
+    // A process that exits on its own fails requests in the request phase.
+    const phase = this.#shuttingDown ? "shutdown" : "request";
+    if (!attributable || err.name === "AbortError") {
+      return withPhase(err, phase);
+    }
+    const status = await Promise.race([
+      this.#exited,
+      new Promise<undefined>((resolve) =>
+        setTimeout(resolve, EXIT_ATTRIBUTION_WINDOW_MS)
+      ),
+    ]);
+    return withPhase(
+      status ? this.#exitedDuringRequestError(status) : err,
+      phase
+    );
+  }
+
+  // Called when `deno run --watch` restarts the script. The restarted script
+  // listens on the same socket path, so we hold back new connections until it
+  // accepts them again and then warm the pool back up.
+  _reload() {
+    const ready = waitForConnectable(
+      this.#socketFile,
+      () => this.#terminated
+    );
+    this.#agent.ready = ready;
+    ready.then(() => this.warmRequest()).catch(() => {});
+  }
+
+  // Resolves once the Deno process accepts requests: its socket file exists,
+  // the script listens on it and the warm requests went through. These are
+  // all part of the same retry loop, so startup has a single way to fail.
+  // Rejects once the worker is terminated or stop returns true.
+  async waitUntilReady(stop: () => boolean) {
+    // Each warm request that is in flight at the same time gets its own
+    // connection, which stays in the pool afterwards.
+    await Promise.all(
+      Array.from({ length: this.#options.warmConnections }, () =>
+        this.warmRequest(stop)
+      )
+    );
   }
 
   // We send this request to Deno so that we get a live connection in the
   // http.Agent and subsequent requests are do not have to wait for a new
   // connection.
-  async warmRequest() {
+  async warmRequest(stop: () => boolean = () => false) {
+    for (let attempt = 0; ; ) {
+      try {
+        return await this.#warmRequestOnce();
+      } catch (err) {
+        if (
+          this.#terminated ||
+          stop() ||
+          err instanceof ProtocolMismatchError ||
+          err instanceof InvalidHandlerError
+        ) {
+          throw err;
+        }
+        // Until the process creates its socket file there is nothing to
+        // connect to, so wait for the file instead of retrying blindly.
+        if (err instanceof SocketGoneError && err.code === "ENOENT") {
+          await waitForSocketFile(
+            this.#socketFile,
+            this.#options.socketWaitStrategy,
+            () => this.#terminated || stop()
+          );
+          continue;
+        }
+        // The socket file can exist before the script listens on it. That is
+        // not a failure, the script just isn't ready yet, so keep waiting
+        // until it is or until the process exits.
+        if (!(err instanceof SocketGoneError)) {
+          if (attempt >= this.#options.warmRequestRetries) {
+            throw err;
+          }
+          attempt++;
+        }
+        await new Promise((resolve) =>
+          setTimeout(resolve, this.#options.warmRequestRetryInterval)
+        );
+      }
+    }
+  }
+
+  #warmRequestOnce() {
     return new Promise<void>((resolve, reject) => {
+      const warmURL = this.#options.warmRequestURL;
       const req = http.request(
         "http://deno",
-        { agent: this.#agent, socketPath: this.#socketFile },
+        {
+          agent: this.#agent,
+          socketPath: this.#socketFile,
+          headers: warmURL
+            ? {
+                "X-Deno-Worker-URL": this.#target(warmURL).href,
+                "X-Deno-Worker-Warmup": "1",
+              }
+            : {},
+        },
         (resp) => {
+          // The bootstrap adds the version to every response, so a missing
+          // one means a bootstrap from before versions were reported.
+          const found = resp.headers["x-deno-worker-protocol"] ?? "";
+          if (found !== String(PROTOCOL_VERSION)) {
+            resp.resume();
+            reject(new ProtocolMismatchError(PROTOCOL_VERSION, String(found)));
+            return;
+          }
+          if (resp.headers["x-deno-worker-error"] === "InvalidHandler") {
+            const chunks: Buffer[] = [];
+            resp.on("data", (chunk) => chunks.push(chunk));
+            resp.on("end", () =>
+              reject(new InvalidHandlerError(Buffer.concat(chunks).toString()))
+            );
+            return;
+          }
           resp.on("error", reject);
           resp.on("data", () => {});
           resp.on("close", () => {
@@ -384,9 +2698,501 @@ class denoHTTPWorker {
     return this.#stderr;
   }
 
+  capturedLogs(): LogLine[] {
+    return [...this.#capturedLogs];
+  }
+
+  get inFlight() {
+    return this.#inFlight;
+  }
+
+  get uptime() {
+    return performance.now() - this.#startedAt;
+  }
+
+  get peakRSS() {
+    return this.#peakRSS;
+  }
+
+  get effectiveRunFlags() {
+    return [...this.#options.runFlags];
+  }
+
+  get socketPath() {
+    return this.#socketFile;
+  }
+
+  waitIdle(): Promise<void> {
+    if (this.#inFlight === 0) {
+      return Promise.resolve();
+    }
+    return new Promise((resolve) => this.#idleWaiters.push(resolve));
+  }
+
+  resetConnections() {
+    if (this.#terminated) {
+      return;
+    }
+    this.#connectionFailures = 0;
+    const old = this.#agent;
+    this.#agent = this.#newAgent();
+    this.#agent.ready = old.ready;
+    old.retire();
+  }
+
+  withProcess<R>(f: (process: MinimalChildProcess) => R): R | undefined {
+    if (this.#terminated) {
+      return undefined;
+    }
+    return f(this.#process);
+  }
+
+  async replace(
+    script: string | URL,
+    options: Partial<DenoWorkerOptions> = {}
+  ): Promise<void> {
+    if (this.#terminated) {
+      throw new DenoWorkerError("Cannot replace a terminated worker");
+    }
+    const next = (await newDenoHTTPWorker(script, {
+      ...this.#createOptions,
+      ...options,
+    })) as denoHTTPWorker;
+    if (this.#terminated) {
+      next.terminate();
+      throw new DenoWorkerError("Cannot replace a terminated worker");
+    }
+    const draining = [...this.#requests];
+    this.#swapProcess(next);
+    await Promise.all(
+      draining.map(
+        (req) => new Promise((resolve) => req.once("close", resolve))
+      )
+    );
+    // next now holds the old process and none of our exit listeners.
+    await next.close();
+  }
+
+  // Exchanges everything that belongs to the process with other, leaving the
+  // exit listeners and in-flight request tracking in place.
+  #swapProcess(other: denoHTTPWorker) {
+    [this.#process, other.#process] = [other.#process, this.#process];
+    [this.#socketFile, other.#socketFile] = [
+      other.#socketFile,
+      this.#socketFile,
+    ];
+    [this.#controlSocketFile, other.#controlSocketFile] = [
+      other.#controlSocketFile,
+      this.#controlSocketFile,
+    ];
+    [this.#stderr, other.#stderr] = [other.#stderr, this.#stderr];
+    [this.#stdout, other.#stdout] = [other.#stdout, this.#stdout];
+    [this.#agent, other.#agent] = [other.#agent, this.#agent];
+    [this.#connectionFailures, other.#connectionFailures] = [
+      other.#connectionFailures,
+      this.#connectionFailures,
+    ];
+    [this.#stderrTail, other.#stderrTail] = [
+      other.#stderrTail,
+      this.#stderrTail,
+    ];
+    [this.#capturedLogs, other.#capturedLogs] = [
+      other.#capturedLogs,
+      this.#capturedLogs,
+    ];
+    [this.#exited, other.#exited] = [other.#exited, this.#exited];
+    [this.#resolveExited, other.#resolveExited] = [
+      other.#resolveExited,
+      this.#resolveExited,
+    ];
+    [this.#processExit, other.#processExit] = [
+      other.#processExit,
+      this.#processExit,
+    ];
+    [this.#outputReaders, other.#outputReaders] = [
+      other.#outputReaders,
+      this.#outputReaders,
+    ];
+    [this.#options, other.#options] = [other.#options, this.#options];
+    [this.#createOptions, other.#createOptions] = [
+      other.#createOptions,
+      this.#createOptions,
+    ];
+    [this.#startedAt, other.#startedAt] = [other.#startedAt, this.#startedAt];
+    [this.#shutdownTimer, other.#shutdownTimer] = [
+      other.#shutdownTimer,
+      this.#shutdownTimer,
+    ];
+    [this.#forced, other.#forced] = [other.#forced, this.#forced];
+    [this.#shuttingDown, other.#shuttingDown] = [
+      other.#shuttingDown,
+      this.#shuttingDown,
+    ];
+    [this.#peakRSS, other.#peakRSS] = [other.#peakRSS, this.#peakRSS];
+    processOwners.set(this.#process, this);
+    processOwners.set(other.#process, other);
+  }
+
+  async reloadScript(): Promise<void> {
+    await this.#control("reload");
+  }
+
+  async drain(): Promise<void> {
+    await this.#control("drain");
+  }
+
+  async stats(): Promise<WorkerStats> {
+    return JSON.parse((await this.#control("stats")).toString());
+  }
+
+  #control(
+    command: string,
+    {
+      params = {},
+      body,
+      failed = (reason) =>
+        new DenoWorkerError(`Control command ${command} failed: ${reason}`),
+    }: {
+      params?: Record<string, string>;
+      body?: string;
+      failed?: (reason: string) => Error;
+    } = {}
+  ): Promise<Buffer> {
+    const socketPath = this.#controlSocketFile;
+    if (!socketPath) {
+      return Promise.reject(
+        new InvalidConfigError(`${command} requires the controlSocket option`)
+      );
+    }
+    const url = new URL(`http://deno/${command}`);
+    for (const [name, value] of Object.entries(params)) {
+      url.searchParams.set(name, value);
+    }
+    return new Promise((resolve, reject) => {
+      const req = http.request(
+        url,
+        { method: "POST", socketPath, agent: false },
+        (resp) => {
+          const chunks: Buffer[] = [];
+          resp.on("error", (err) => this.#attributeError(err).then(reject));
+          resp.on("data", (chunk) => chunks.push(chunk));
+          resp.on("end", () => {
+            const result = Buffer.concat(chunks);
+            if (resp.statusCode !== 200 && resp.statusCode !== 204) {
+              reject(failed(result.toString()));
+              return;
+            }
+            resolve(result);
+          });
+        }
+      );
+      req.on("error", (err) => this.#attributeError(err).then(reject));
+      req.end(body);
+    });
+  }
+
   addEventListener(type: "exit", listener: OnExitListener): void {
     this.#onexitListeners.push(listener as OnExitListener);
   }
+
+  removeEventListener(type: "exit", listener: OnExitListener): void {
+    this.#onexitListeners = this.#onexitListeners.filter(
+      (l) => l !== listener
+    );
+  }
+}
+
+// An http.Agent that waits for the worker to be ready before it opens new
+// connections to the socket. Connections are only handed to the request once
+// they are established, so that failing to connect is reported as a
+// SocketGoneError rather than a generic request error.
+class workerAgent extends http.Agent {
+  ready: Promise<void> = Promise.resolve();
+  connectTimeout?: number;
+
+  // Closes the idle connections, and the ones in use once their requests are
+  // done, but unlike destroy leaves the requests on them alone.
+  retire() {
+    // Without keepAlive the agent closes sockets instead of pooling them when
+    // they are released.
+    (this as { keepAlive?: boolean }).keepAlive = false;
+    for (const sockets of Object.values(this.freeSockets)) {
+      for (const socket of sockets ?? []) socket.destroy();
+    }
+  }
+
+  createConnection(
+    options: net.IpcNetConnectOpts,
+    callback: (err: Error | null, socket?: net.Socket) => void
+  ) {
+    this.ready.then(
+      () => {
+        const socket = net.createConnection(options);
+        const timeout = this.connectTimeout;
+        const timer =
+          timeout === undefined
+            ? undefined
+            : setTimeout(() => {
+                socket.off("error", onError);
+                socket.destroy();
+                callback(new ConnectTimeoutError(options.path, timeout));
+              }, timeout);
+        const onError = (err: NodeJS.ErrnoException) => {
+          clearTimeout(timer);
+          callback(
+            err.code === "ENOENT" || err.code === "ECONNREFUSED"
+              ? new SocketGoneError(options.path, err.code)
+              : err
+          );
+        };
+        socket.once("error", onError);
+        socket.once("connect", () => {
+          clearTimeout(timer);
+          socket.off("error", onError);
+          socketConnectedAt.set(socket, performance.now());
+          callback(null, socket);
+        });
+      },
+      (err) => callback(err)
+    );
+    return undefined;
+  }
+}
+
+const CONNECTION_ERROR_CODES = new Set([
+  "ECONNRESET",
+  "ECONNREFUSED",
+  "ENOENT",
+  "EPIPE",
+]);
+
+// Whether err means the connection to the Deno process failed, as opposed to
+// the request being aborted by the caller.
+function isConnectionError(err: NodeJS.ErrnoException) {
+  return (
+    err instanceof ConnectTimeoutError ||
+    (err.code !== undefined && CONNECTION_ERROR_CODES.has(err.code))
+  );
+}
+
+// Resolves once the socket file exists, or once stop returns true. With the
+// "watch" strategy we are woken up by file system events on the socket's
+// directory and only poll as a fallback, in case an event is missed or
+// watching isn't supported.
+async function waitForSocketFile(
+  socketFile: string,
+  strategy: DenoWorkerOptions["socketWaitStrategy"],
+  stop: () => boolean
+) {
+  let watcher: FSWatcher | undefined;
+  let wake = () => {};
+  if (strategy === "watch") {
+    try {
+      watcher = watch(path.dirname(socketFile), (_event, filename) => {
+        if (filename === path.basename(socketFile)) wake();
+      });
+      watcher.on("error", () => {});
+    } catch {
+      // Fall back to polling.
+    }
+  }
+  try {
+    while (!stop()) {
+      try {
+        await fs.stat(socketFile);
+        // File exists
+        return;
+      } catch (err) {
+        await new Promise<void>((resolve) => {
+          wake = resolve;
+          setTimeout(resolve, watcher ? 100 : 20);
+        });
+      }
+    }
+  } finally {
+    watcher?.close();
+  }
+}
+
+// Resolves once a connection to the socket succeeds. Retries until then, or
+// until stop returns true.
+async function waitForConnectable(socketFile: string, stop: () => boolean) {
+  while (!stop()) {
+    const connected = await new Promise<boolean>((resolve) => {
+      const socket = net.createConnection(socketFile);
+      socket.on("connect", () => {
+        socket.destroy();
+        resolve(true);
+      });
+      socket.on("error", () => resolve(false));
+    });
+    if (connected) {
+      return;
+    }
+    await new Promise((resolve) => setTimeout(resolve, 20));
+  }
+}
+
+function parseURL(url: string | URL): URL {
+  if (url instanceof URL) {
+    return url;
+  }
+  try {
+    return new URL(url);
+  } catch {
+    throw new InvalidUrlError(url);
+  }
+}
+
+// Whether name is one of the X-Deno-Worker-* headers the worker and the
+// bootstrap script use to talk to each other.
+function isInternalHeader(name: string): boolean {
+  return name.toLowerCase().startsWith("x-deno-worker-");
+}
+
+// Returns the base path with a single leading slash and no trailing slash, or
+// "" if there is no base path.
+function normalizeBasePath(basePath: string | undefined): string {
+  const trimmed = (basePath ?? "").replace(/^\/+|\/+$/g, "");
+  return trimmed ? "/" + trimmed : "";
+}
+
+// Writes the body to the request and ends it. Errors raised while streaming
+// the body destroy the request, so they surface as request errors.
+function writeBody(req: http.ClientRequest, body?: RequestBody) {
+  if (
+    body === undefined ||
+    typeof body === "string" ||
+    body instanceof Uint8Array
+  ) {
+    req.end(body);
+    return;
+  }
+  const source = body instanceof Readable ? body : Readable.from(body);
+  pipeline(source, req, () => {});
+}
+
+// Returns Deno's compile errors from stderr if they are about the bootstrap
+// script itself, rather than the worker's script or an exception at runtime.
+function bootstrapCompileErrors(
+  stderr: string,
+  bootstrapPath: string
+): string | undefined {
+  const start = stderr.search(/^error: (?!Uncaught)/m);
+  if (start === -1) {
+    return undefined;
+  }
+  const diagnostics = stderr.slice(start).trim();
+  return diagnostics.includes(pathToFileURL(bootstrapPath).href)
+    ? diagnostics
+    : undefined;
+}
+
+// Records the lifecycle phase err happened in as err.phase, unless an earlier
+// phase was recorded already, and returns err.
+function withPhase<E extends Error>(err: E, phase: WorkerPhase): E {
+  if ((err as { phase?: WorkerPhase }).phase === undefined) {
+    Object.assign(err, { phase });
+  }
+  return err;
+}
+
+// Returns headers with "Transfer-Encoding: chunked" added, unless they frame
+// the body already. headers is either an object or a [name, value, ...] list.
+function withChunkedEncoding(
+  headers: http.RequestOptions["headers"]
+): http.RequestOptions["headers"] {
+  const list = Array.isArray(headers) ? (headers as string[]) : undefined;
+  const names = list
+    ? list.filter((_, i) => i % 2 === 0)
+    : Object.keys(headers ?? {});
+  const framed = names.some((name) =>
+    ["content-length", "transfer-encoding"].includes(name.toLowerCase())
+  );
+  if (framed) {
+    return headers;
+  }
+  return list
+    ? [...list, "Transfer-Encoding", "chunked"]
+    : { ...headers, "Transfer-Encoding": "chunked" };
+}
+
+// Returns headers with the defaults added that aren't set already, comparing
+// names case-insensitively.
+function withDefaultHeaders(
+  headers: http.OutgoingHttpHeaders,
+  defaults: Record<string, string>
+): http.OutgoingHttpHeaders {
+  const names = new Set(Object.keys(headers).map((name) => name.toLowerCase()));
+  const merged = { ...headers };
+  for (const [name, value] of Object.entries(defaults)) {
+    if (!names.has(name.toLowerCase())) {
+      merged[name] = value;
+    }
+  }
+  return merged;
+}
+
+// Socket files are removed in exactly one place: by the worker when it
+// terminates, or by newDenoHTTPWorker if the process exits before there is a
+// worker. Files that are already gone are fine.
+async function removeSocketFiles(files: string[]) {
+  await Promise.all(files.map((file) => fs.rm(file).catch(() => {})));
+}
+
+// Removes the headers whose lowercase name remove returns true for from both
+// the parsed headers and rawHeaders, so that neither view shows them.
+function removeResponseHeaders(
+  response: http.IncomingMessage,
+  remove: (name: string) => boolean
+) {
+  const rawHeaders: string[] = [];
+  for (let i = 0; i < response.rawHeaders.length; i += 2) {
+    const name = response.rawHeaders[i]!;
+    const value = response.rawHeaders[i + 1]!;
+    if (!remove(name.toLowerCase())) {
+      rawHeaders.push(name, value);
+    }
+  }
+  response.rawHeaders = rawHeaders;
+  const headers = response.headers;
+  for (const name of Object.keys(headers)) {
+    if (remove(name)) {
+      delete headers[name];
+    }
+  }
+}
+
+function parseJSONLine(line: string): unknown {
+  if (!line.startsWith("{")) {
+    return undefined;
+  }
+  try {
+    return JSON.parse(line);
+  } catch {
+    return undefined;
+  }
+}
+
+// Reads the lines of one of the process's output streams.
+interface OutputReader {
+  lines: readline.Interface;
+  closed: Promise<void>;
+}
+
+// Keeps the most recent output written to a stream.
+class outputTail {
+  #tail = "";
+
+  constructor(stream: Readable | null) {
+    stream?.on("data", (chunk) => {
+      this.#tail = (this.#tail + chunk.toString()).slice(-OUTPUT_TAIL_LENGTH);
+    });
+  }
+
+  toString() {
+    return this.#tail;
+  }
 }
 
 /**
@@ -0,0 +1,321 @@
+/**
+ * The part of a worker's lifecycle an error happened in. "spawn" is starting
+ * the Deno process, "startup" is waiting for its socket and the warm requests,
+ * "request" is a live request and "shutdown" is a request that failed after
+ * shutdown or terminate was called.
+ */
+export type WorkerPhase = "spawn" | "startup" | "request" | "shutdown";
+
+/**
+ * Base class for all errors raised by deno-http-worker. Use `instanceof` on
+ * the subclasses below to tell the different failure modes apart.
+ */
+export class DenoWorkerError extends Error {
+  /**
+   * The lifecycle phase the error happened in. Other errors rejected by the
+   * worker, such as socket errors, have a phase property as well.
+   */
+  phase?: WorkerPhase;
+
+  constructor(message: string) {
+    super(message);
+    this.name = new.target.name;
+  }
+}
+
+/**
+ * The Deno process exited while a request was still waiting for its
+ * response.
+ */
+export class WorkerExitedDuringRequestError extends DenoWorkerError {
+  readonly code: number;
+  readonly signal: string;
+  /**
+   * The last output the process wrote to stderr before exiting.
+   */
+  readonly stderrTail: string;
+
+  constructor(code: number, signal: string, stderrTail: string) {
+    super(
+      `Deno process exited during request (code: ${code}, signal: ${
+        signal || "none"
+      })`
+    );
+    this.code = code;
+    this.signal = signal;
+    this.stderrTail = stderrTail;
+  }
+}
+
+/**
+ * The socket path is longer than the operating system allows for Unix socket
+ * paths, usually because the temp directory has a long path.
+ */
+export class SocketPathTooLongError extends DenoWorkerError {
+  readonly path: string;
+  readonly limit: number;
+
+  constructor(path: string, limit: number) {
+    super(
+      `Socket path ${path} is longer than the limit of ${limit} bytes. ` +
+        "Set the socketDir option to a directory with a shorter path."
+    );
+    this.path = path;
+    this.limit = limit;
+  }
+}
+
+/**
+ * The socket directory doesn't exist or isn't writable, for example because
+ * the temp directory is read-only in a locked-down container, so the Deno
+ * process could not create its socket.
+ */
+export class SocketDirNotWritableError extends DenoWorkerError {
+  readonly dir: string;
+
+  constructor(dir: string, code: string) {
+    super(
+      `Socket directory ${dir} is not writable (${code}). ` +
+        "Set the socketDir option to a writable directory."
+    );
+    this.dir = dir;
+  }
+}
+
+/**
+ * The Deno process did not become ready within the startupTimeout option. The
+ * output it printed so far usually tells why, for example a slow module
+ * download or a type error.
+ */
+export class StartupTimeoutError extends DenoWorkerError {
+  readonly timeout: number;
+  readonly stdoutTail: string;
+  readonly stderrTail: string;
+  /**
+   * Whether the process was still running when the timeout expired. It is
+   * killed afterwards.
+   */
+  readonly stillRunning: boolean;
+
+  constructor(
+    timeout: number,
+    stdoutTail: string,
+    stderrTail: string,
+    stillRunning: boolean
+  ) {
+    super(`Deno process was not ready after ${timeout}ms`);
+    this.timeout = timeout;
+    this.stdoutTail = stdoutTail;
+    this.stderrTail = stderrTail;
+    this.stillRunning = stillRunning;
+  }
+}
+
+/**
+ * The bootstrap script speaks a different protocol version than this version
+ * of deno-http-worker expects, which happens when a custom bootstrap script
+ * is out of date.
+ */
+export class ProtocolMismatchError extends DenoWorkerError {
+  readonly expected: number;
+  /**
+   * The version the bootstrap reported, or "" if it didn't report one.
+   */
+  readonly found: string;
+
+  constructor(expected: number, found: string) {
+    super(
+      `Bootstrap script protocol version ${found || "(none)"} does not ` +
+        `match the expected version ${expected}`
+    );
+    this.expected = expected;
+    this.found = found;
+  }
+}
+
+/**
+ * The bootstrap script that runs in the Deno process failed to compile, for
+ * example because a custom denoBootstrapScriptPath has a syntax error or was
+ * written for a different Deno version. The script passed to the worker was
+ * never loaded.
+ */
+export class BootstrapCompileError extends DenoWorkerError {
+  /**
+   * The errors Deno reported.
+   */
+  readonly diagnostics: string;
+
+  constructor(diagnostics: string) {
+    super(`Bootstrap script failed to compile:\n${diagnostics}`);
+    this.diagnostics = diagnostics;
+  }
+}
+
+/**
+ * The script loaded, but its default export is missing or has no fetch
+ * function, so there is nothing to handle requests with.
+ */
+export class InvalidHandlerError extends DenoWorkerError {
+  readonly reason: string;
+
+  constructor(reason: string) {
+    super(`Script has no valid handler: ${reason}`);
+    this.reason = reason;
+  }
+}
+
+/**
+ * The options passed to newDenoHTTPWorker are invalid.
+ */
+export class InvalidConfigError extends DenoWorkerError {}
+
+/**
+ * The URL passed to a request is not a valid absolute URL.
+ */
+export class InvalidUrlError extends DenoWorkerError {
+  readonly url: string;
+
+  constructor(url: string) {
+    super(`Invalid request URL: ${JSON.stringify(url)}`);
+    this.url = url;
+  }
+}
+
+/**
+ * The Deno executable could not be found.
+ */
+export class DenoNotFoundError extends DenoWorkerError {
+  readonly executable: string;
+
+  constructor(executable: string) {
+    super(
+      `Deno executable ${JSON.stringify(executable)} was not found. ` +
+        "Install Deno (https://docs.deno.com/runtime/manual/getting_started/installation) " +
+        "or set the denoExecutable option to the path of the deno binary."
+    );
+    this.executable = executable;
+  }
+}
+
+/**
+ * The script needed a permission that the worker wasn't started with, for
+ * example network access without --allow-net. Permissions are never prompted
+ * for, so the missing permission fails the request instead.
+ */
+export class PermissionDeniedError extends DenoWorkerError {}
+
+/**
+ * The response body is larger than the maxResponseBytes option allows.
+ */
+export class ResponseTooLargeError extends DenoWorkerError {
+  readonly limit: number;
+
+  constructor(limit: number) {
+    super(`Response body exceeds the limit of ${limit} bytes`);
+    this.limit = limit;
+  }
+}
+
+/**
+ * The connection closed before the whole response body arrived, for example
+ * because the handler failed while streaming it.
+ */
+export class IncompleteResponseError extends DenoWorkerError {
+  /**
+   * How many bytes of the body arrived before the connection closed.
+   */
+  readonly receivedBytes: number;
+  /**
+   * Set if the response was cut short because the Deno process exited.
+   */
+  readonly exit: WorkerExitedDuringRequestError | undefined;
+
+  constructor(
+    receivedBytes: number,
+    exit: WorkerExitedDuringRequestError | undefined
+  ) {
+    super(
+      `Response ended after ${receivedBytes} bytes of the body` +
+        (exit ? `: ${exit.message}` : "")
+    );
+    this.receivedBytes = receivedBytes;
+    this.exit = exit;
+  }
+}
+
+/**
+ * The response body could not be decoded as the requested type, for example
+ * because it isn't valid JSON.
+ */
+export class ResponseDecodeError extends DenoWorkerError {
+  readonly statusCode: number;
+  readonly body: Buffer;
+
+  constructor(statusCode: number, body: Buffer, reason: string) {
+    super(`Could not decode response (status ${statusCode}): ${reason}`);
+    this.statusCode = statusCode;
+    this.body = body;
+  }
+}
+
+/**
+ * The worker's socket could not be connected to because it no longer exists
+ * or nothing is listening on it anymore, for example because a temp directory
+ * cleaner removed the socket file. The worker needs to be replaced.
+ */
+export class SocketGoneError extends DenoWorkerError {
+  readonly socketPath: string;
+  /**
+   * The underlying error code, "ENOENT" or "ECONNREFUSED".
+   */
+  readonly code: string;
+
+  constructor(socketPath: string, code: string) {
+    super(`Could not connect to worker socket ${socketPath} (${code})`);
+    this.socketPath = socketPath;
+    this.code = code;
+  }
+}
+
+/**
+ * Connecting to the worker's socket took longer than the connectTimeout
+ * option allows.
+ */
+export class ConnectTimeoutError extends DenoWorkerError {
+  readonly socketPath: string;
+  readonly timeout: number;
+
+  constructor(socketPath: string, timeout: number) {
+    super(
+      `Timed out after ${timeout}ms connecting to worker socket ${socketPath}`
+    );
+    this.socketPath = socketPath;
+    this.timeout = timeout;
+  }
+}
+
+/**
+ * The response did not finish within the requestTimeout option. The request
+ * is aborted.
+ */
+export class RequestTimeoutError extends DenoWorkerError {
+  readonly timeout: number;
+
+  constructor(timeout: number) {
+    super(`Request did not finish within ${timeout}ms`);
+    this.timeout = timeout;
+  }
+}
+
+/**
+ * A script passed to registerScript could not be loaded, for example because
+ * it failed to compile or has no default export with a fetch function.
+ */
+export class ScriptRegistrationError extends DenoWorkerError {
+  readonly key: string;
+
+  constructor(key: string, reason: string) {
+    super(`Failed to register script ${JSON.stringify(key)}: ${reason}`);
+    this.key = key;
+  }
+}
@@ -1,5 +1,47 @@
 export {
+  BatchRequest,
+  CollectedResponse,
+  ConnectionInfo,
   DenoHTTPWorker,
   DenoWorkerOptions,
+  ExitDetails,
+  InterceptedRequest,
+  JSONResponse,
+  LogLine,
+  MinimalChildProcess,
+  RequestBody,
+  ResolvedCommand,
+  ResourceLimits,
+  RetryPolicy,
+  TextResponse,
+  TraceContext,
+  WorkerStats,
   newDenoHTTPWorker,
+  newDenoHTTPWorkerFromBytes,
+  newDenoHTTPWorkerFromPath,
+  newDenoHTTPWorkers,
+  resolveWorkerCommand,
+  withForwardedFor,
 } from "./DenoHTTPWorker.js";
+export {
+  BootstrapCompileError,
+  ConnectTimeoutError,
+  DenoNotFoundError,
+  DenoWorkerError,
+  IncompleteResponseError,
+  InvalidConfigError,
+  InvalidHandlerError,
+  InvalidUrlError,
+  PermissionDeniedError,
+  ProtocolMismatchError,
+  RequestTimeoutError,
+  ResponseDecodeError,
+  ResponseTooLargeError,
+  ScriptRegistrationError,
+  SocketDirNotWritableError,
+  SocketGoneError,
+  SocketPathTooLongError,
+  StartupTimeoutError,
+  WorkerExitedDuringRequestError,
+  WorkerPhase,
+} from "./errors.js";
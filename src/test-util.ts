@@ -0,0 +1,156 @@
+// Helpers for testing code that streams request and response bodies through a
+// worker. They are imported from "deno-http-worker/dist/test-util.js" and are
+// not part of the main entry point.
+import http from "node:http";
+import {
+  DenoHTTPWorker,
+  DenoWorkerOptions,
+  newDenoHTTPWorker,
+} from "./DenoHTTPWorker.js";
+
+/**
+ * A script that streams the request body back as the response body, chunk by
+ * chunk, without waiting for the request to end.
+ */
+export const STREAMING_ECHO_SCRIPT = `
+  export default {
+    async fetch(req: Request): Promise<Response> {
+      return new Response(req.body);
+    },
+  };
+`;
+
+/**
+ * A script that never reads the request body, so a client writing a large
+ * body eventually sees backpressure.
+ */
+export const STALLED_READER_SCRIPT = `
+  export default {
+    async fetch(req: Request): Promise<Response> {
+      await new Promise(() => {});
+      return new Response(null);
+    },
+  };
+`;
+
+/**
+ * Start a worker that runs STREAMING_ECHO_SCRIPT.
+ */
+export const newStreamingEchoWorker = (
+  options: Partial<DenoWorkerOptions> = {}
+): Promise<DenoHTTPWorker> => newDenoHTTPWorker(STREAMING_ECHO_SCRIPT, options);
+
+/**
+ * Send chunks to a worker running STREAMING_ECHO_SCRIPT one at a time, and
+ * only write the next chunk once the previous one came back. This only
+ * finishes if each chunk is delivered on its own in both directions, so it
+ * rejects when either side buffers the whole body, or after chunkTimeout
+ * milliseconds without an echoed chunk. Resolves with the chunks as they were
+ * received.
+ */
+export function echoChunksIncrementally(
+  worker: DenoHTTPWorker,
+  chunks: (string | Buffer)[],
+  chunkTimeout = 1000
+): Promise<Buffer[]> {
+  return new Promise((resolve, reject) => {
+    const received: Buffer[] = [];
+    let pending = Buffer.alloc(0);
+    let next = 0;
+    let timer: NodeJS.Timeout | undefined;
+    const fail = (err: Error) => {
+      clearTimeout(timer);
+      req.destroy();
+      reject(err);
+    };
+    const writeNext = () => {
+      clearTimeout(timer);
+      if (next === chunks.length) {
+        req.end();
+        return;
+      }
+      req.write(chunks[next] as string | Buffer);
+      next++;
+      timer = setTimeout(
+        () => fail(new Error(`chunk ${next - 1} was not echoed in time`)),
+        chunkTimeout
+      );
+    };
+    const req = worker.request(
+      "http://localhost/",
+      { method: "POST" },
+      (resp: http.IncomingMessage) => {
+        resp.on("data", (data: Buffer) => {
+          pending = Buffer.concat([pending, data]);
+          const expected = Buffer.from(chunks[received.length] ?? "");
+          if (pending.length >= expected.length) {
+            received.push(pending.subarray(0, expected.length));
+            pending = pending.subarray(expected.length);
+            writeNext();
+          }
+        });
+        resp.on("end", () => {
+          clearTimeout(timer);
+          resolve(received);
+        });
+        resp.on("error", fail);
+      }
+    );
+    req.on("error", fail);
+    req.flushHeaders();
+    writeNext();
+  });
+}
+
+/**
+ * Write chunkSize byte chunks of a request body to a worker running
+ * STALLED_READER_SCRIPT until the request stream reports backpressure, or
+ * until maxBytes were written. Resolves with the number of bytes written, or
+ * undefined if there was no backpressure. The request is aborted afterwards.
+ */
+export function writeUntilBackpressure(
+  worker: DenoHTTPWorker,
+  chunkSize = 64 * 1024,
+  maxBytes = 64 * 1024 * 1024
+): Promise<number | undefined> {
+  return new Promise((resolve, reject) => {
+    const req = worker.request(
+      "http://localhost/",
+      { method: "POST" },
+      () => {}
+    );
+    // Set when the helper aborts the request itself once it is done.
+    let finished = false;
+    const finish = (result: number | undefined) => {
+      finished = true;
+      req.destroy();
+      resolve(result);
+    };
+    req.on("error", (err) => {
+      if (!finished) reject(err);
+    });
+    const chunk = Buffer.alloc(chunkSize);
+    let written = 0;
+    const writeMore = () => {
+      while (written < maxBytes) {
+        if (req.destroyed) return;
+        written += chunk.length;
+        if (!req.write(chunk)) {
+          // Give the socket a moment to drain what it can: if the body is
+          // really not read, the stream stays full.
+          setTimeout(() => {
+            if (req.destroyed) return;
+            if (req.writableNeedDrain) {
+              finish(written);
+            } else {
+              writeMore();
+            }
+          }, 100);
+          return;
+        }
+      }
+      finish(undefined);
+    };
+    writeMore();
+  });
+}
@@ -0,0 +1,24 @@
+// A bootstrap that closes the first connections without answering, as many
+// as its last argument says, and answers warm requests after that.
+const socketFile = Deno.args[0];
+const failures = Number(Deno.args[Deno.args.length - 1]);
+
+const response = new TextEncoder().encode(
+  "HTTP/1.1 200 OK\r\n" +
+    "X-Deno-Worker-Protocol: 1\r\n" +
+    "Content-Length: 2\r\n" +
+    "Connection: close\r\n" +
+    "\r\n" +
+    "ok",
+);
+
+let connections = 0;
+for await (const conn of Deno.listen({ transport: "unix", path: socketFile })) {
+  if (connections++ < failures) {
+    conn.close();
+    continue;
+  }
+  await conn.read(new Uint8Array(64 * 1024));
+  await conn.write(response);
+  conn.close();
+}
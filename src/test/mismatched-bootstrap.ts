@@ -0,0 +1,10 @@
+// A bootstrap that reports a protocol version no worker expects.
+const socketFile = Deno.args[0];
+
+Deno.serve(
+  { path: socketFile, onListen() {} },
+  () =>
+    Response.json({ warming: true }, {
+      headers: { "X-Deno-Worker-Protocol": "0" },
+    }),
+);
@@ -0,0 +1,15 @@
+// A bootstrap that creates its socket path well before it starts accepting
+// connections on it.
+const socketFile = Deno.args[0];
+
+Deno.writeTextFileSync(socketFile, "");
+await new Promise((resolve) => setTimeout(resolve, 300));
+Deno.removeSync(socketFile);
+
+Deno.serve(
+  { path: socketFile, onListen() {} },
+  () =>
+    Response.json({ ok: true }, {
+      headers: { "X-Deno-Worker-Protocol": "1" },
+    }),
+);
@@ -0,0 +1,7 @@
+// A bootstrap from before the protocol version was reported.
+const socketFile = Deno.args[0];
+
+Deno.serve(
+  { path: socketFile, onListen() {} },
+  () => Response.json({ warming: true }),
+);